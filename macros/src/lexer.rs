@@ -1,25 +1,40 @@
 use crate::parser::{ParseArg, Parser};
-use proc_macro2::{token_stream, Delimiter, Ident, Literal, Span, TokenStream, TokenTree};
+use proc_macro2::{token_stream, Delimiter, Group, Ident, Literal, Span, TokenStream, TokenTree};
 use proc_macro_error::abort;
 use quote::quote;
+use std::collections::VecDeque;
 use std::ffi::OsString;
 use std::iter::Peekable;
+use std::str::FromStr;
 
 // Scan string literal to tokenstream, used by most of the macros
 //
-// - support ${var} or $var for interpolation
+// - support ${var} or $var for interpolation, and ${env.NAME} for environment variable
 //   - to escape '$' itself, use "$$"
 // - support normal rust character escapes:
 //   https://doc.rust-lang.org/reference/tokens.html#ascii-escapes
 pub fn scan_str_lit(lit: &Literal) -> TokenStream {
+    scan_str_lit_with_subst(lit, false, false).0
+}
+
+// Scan string literal for command arguments, with "$(cmds)" for command substitution
+//
+// - "${env.NAME}" is read when the command is spawned if `lazy_env` is set
+// - returns whether any substitution is found, which makes the output a `SubstArg`
+fn scan_str_lit_with_subst(
+    lit: &Literal,
+    allow_subst: bool,
+    lazy_env: bool,
+) -> (TokenStream, bool) {
     let s = lit.to_string();
     if !s.starts_with('\"') {
-        return quote!(::cmd_lib::CmdString::from(#lit));
+        return (quote!(::cmd_lib::CmdString::from(#lit)), false);
     }
     let mut iter = s[1..s.len() - 1] // To trim outside ""
         .chars()
         .peekable();
     let mut output = quote!(::cmd_lib::CmdString::default());
+    let mut found_subst = false;
     let mut last_part = OsString::new();
     fn seal_last_part(last_part: &mut OsString, output: &mut TokenStream) {
         if !last_part.is_empty() {
@@ -39,6 +54,38 @@ pub fn scan_str_lit(lit: &Literal) -> TokenStream {
             }
 
             seal_last_part(&mut last_part, &mut output);
+            if allow_subst && iter.peek() == Some(&'(') {
+                iter.next();
+                let mut cmds_str = String::new();
+                let mut depth = 1;
+                for c in iter.by_ref() {
+                    if c == '(' {
+                        depth += 1;
+                    } else if c == ')' {
+                        depth -= 1;
+                        if depth == 0 {
+                            break;
+                        }
+                    }
+                    cmds_str.push(c);
+                }
+                if depth != 0 {
+                    abort!(lit.span(), "bad command substitution");
+                }
+                // unescape the commands as a string literal
+                let cmds_lit = syn::parse_str::<syn::LitStr>(&format!("\"{}\"", cmds_str))
+                    .unwrap_or_else(|_| abort!(lit.span(), "bad command substitution"));
+                let cmds = Lexer::from_str(&cmds_lit.value(), lit.span())
+                    .scan()
+                    .parse(false);
+                output = if found_subst {
+                    quote!(#output.append_cmds(#cmds))
+                } else {
+                    quote!(::cmd_lib::SubstArg::from(#output).append_cmds(#cmds))
+                };
+                found_subst = true;
+                continue;
+            }
             let mut with_brace = false;
             if iter.peek() == Some(&'{') {
                 with_brace = true;
@@ -55,6 +102,31 @@ pub fn scan_str_lit(lit: &Literal) -> TokenStream {
                 var.push(c);
                 iter.next();
             }
+            if with_brace && var == "env" && iter.peek() == Some(&'.') {
+                // "${env.NAME}" for environment variable
+                iter.next();
+                let mut name = String::new();
+                while let Some(&c) = iter.peek() {
+                    if !c.is_ascii_alphanumeric() && c != '_' {
+                        break;
+                    }
+                    name.push(c);
+                    iter.next();
+                }
+                if name.is_empty() || iter.next() != Some('}') {
+                    abort!(lit.span(), "bad environment variable substitution");
+                }
+                if !lazy_env {
+                    output
+                        .extend(quote!(.append(::cmd_lib::CmdString::default().append_env(#name))));
+                } else if found_subst {
+                    output = quote!(#output.append_env(#name));
+                } else {
+                    output = quote!(::cmd_lib::SubstArg::from(#output).append_env(#name));
+                    found_subst = true;
+                }
+                continue;
+            }
             if with_brace {
                 if iter.peek() != Some(&'}') {
                     abort!(lit.span(), "bad substitution");
@@ -73,26 +145,84 @@ pub fn scan_str_lit(lit: &Literal) -> TokenStream {
         }
     }
     seal_last_part(&mut last_part, &mut output);
-    output
+    (output, found_subst)
+}
+
+// split words by whitespaces, keeping quoted strings and groupings together
+fn split_words(s: &str) -> Vec<String> {
+    let mut words = vec![];
+    let mut word = String::new();
+    let mut quoted = false;
+    let mut escaped = false;
+    let mut depth = 0;
+    for c in s.chars() {
+        if quoted {
+            if escaped {
+                escaped = false;
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == '"' {
+                quoted = false;
+            }
+        } else if c == '"' {
+            quoted = true;
+        } else if matches!(c, '(' | '[' | '{') {
+            depth += 1;
+        } else if matches!(c, ')' | ']' | '}') {
+            depth -= 1;
+        } else if c.is_whitespace() && depth == 0 {
+            if !word.is_empty() {
+                words.push(std::mem::take(&mut word));
+            }
+            continue;
+        }
+        word.push(c);
+    }
+    if !word.is_empty() {
+        words.push(word);
+    }
+    words
 }
 
+// supported macro attributes, and whether they need a value
+const ATTRIBUTES: &[(&str, bool)] = &[
+    ("timeout", true),
+    ("kill_grace", true),
+    ("stderr", true),
+    ("glob", true),
+    ("piped_stdin", false),
+    ("env_clear", false),
+    ("env_keep", true),
+    ("env_remove", true),
+];
+
+// attributes which can also be set for each pipeline
+const PIPELINE_ATTRIBUTES: &[&str] = &["env_clear", "env_keep", "env_remove"];
+
 enum SepToken {
     Space,
     SemiColon,
     Pipe,
+    And,
+    Or,
+    Background,
 }
 
 enum RedirectFd {
     Stdin,
+    StdinData { newline: bool },
     Stdout { append: bool },
     Stderr { append: bool },
     StdoutErr { append: bool },
+    FdIn { fd: i32, rw: bool },
+    FdOut { fd: i32, append: bool },
 }
 
 pub struct Lexer {
     iter: TokenStreamPeekable<token_stream::IntoIter>,
     args: Vec<ParseArg>,
     last_arg_str: TokenStream,
+    last_arg_subst: bool,
     last_redirect: Option<(RedirectFd, Span)>,
     seen_redirect: (bool, bool, bool),
 }
@@ -102,18 +232,59 @@ impl Lexer {
         Self {
             args: vec![],
             last_arg_str: TokenStream::new(),
+            last_arg_subst: false,
             last_redirect: None,
             seen_redirect: (false, false, false),
             iter: TokenStreamPeekable {
                 peekable: input.into_iter().peekable(),
                 span: Span::call_site(),
+                gaps: None,
             },
         }
     }
 
+    // lexer for commands inside a string literal, whose tokens have no useful spans to find
+    // the spaces between them, so the words are split by whitespaces first
+    fn from_str(s: &str, span: Span) -> Self {
+        let mut tokens = vec![];
+        let mut gaps = VecDeque::new();
+        for word in split_words(s) {
+            let stream = TokenStream::from_str(&word)
+                .unwrap_or_else(|_| abort!(span, "invalid command substitution: {}", s));
+            for (i, mut tt) in stream.into_iter().enumerate() {
+                tt.set_span(span);
+                tokens.push(tt);
+                gaps.push_back(i == 0);
+            }
+        }
+        let mut lexer = Self::new(tokens.into_iter().collect());
+        lexer.iter.gaps = Some(gaps);
+        lexer
+    }
+
+    // nested groupings inside string literal don't keep the spaces between their tokens
+    fn check_nested_group(&self, g: &Group) {
+        if self.iter.gaps.is_some() {
+            abort!(
+                g.span(),
+                "nested substitution is not supported inside string literal"
+            );
+        }
+    }
+
     pub fn scan(mut self) -> Parser<impl Iterator<Item = ParseArg>> {
         while let Some(item) = self.iter.next() {
             match item {
+                TokenTree::Group(g)
+                    if g.delimiter() == Delimiter::Parenthesis && self.is_cmd_start() =>
+                {
+                    self.scan_subshell(g);
+                }
+                TokenTree::Group(g)
+                    if g.delimiter() == Delimiter::Bracket && self.last_redirect.is_none() =>
+                {
+                    self.scan_bracket_pattern(g);
+                }
                 TokenTree::Group(_) => {
                     abort!(self.iter.span(), "grouping is only allowed for variables");
                 }
@@ -130,14 +301,31 @@ impl Lexer {
                         self.add_arg_with_token(SepToken::SemiColon, self.iter.span());
                     } else if ch == '|' {
                         self.scan_pipe();
+                    } else if (ch == '<' || ch == '>') && self.is_proc_subst() {
+                        self.scan_proc_subst(ch == '<');
                     } else if ch == '<' {
-                        self.set_redirect(self.iter.span(), RedirectFd::Stdin);
+                        self.scan_redirect_in(0);
                     } else if ch == '>' {
                         self.scan_redirect_out(1);
                     } else if ch == '&' {
                         self.scan_ampersand();
                     } else if ch == '$' {
                         self.scan_dollar();
+                    } else if ch == '#' && self.is_attribute() {
+                        self.scan_attribute();
+                    } else if ch == '!' && self.is_negate() {
+                        if matches!(self.args.last(), Some(ParseArg::Pipe)) {
+                            abort!(
+                                self.iter.span(),
+                                "\"!\" is only allowed at the start of a pipeline"
+                            );
+                        }
+                        self.args.push(ParseArg::Negate(self.iter.span()));
+                    } else if ch == '~' && self.last_arg_str.is_empty() && self.is_tilde_end() {
+                        self.scan_tilde();
+                    } else if (ch == '*' || ch == '?') && self.last_redirect.is_none() {
+                        let s = ch.to_string();
+                        self.extend_last_arg_subst(quote!(.append_pattern(#s)));
                     } else {
                         let s = ch.to_string();
                         self.extend_last_arg(quote!(#s));
@@ -159,29 +347,61 @@ impl Lexer {
             if last_arg_str.is_empty() {
                 abort!(span, "wrong redirection format: missing target");
             }
+            if self.last_arg_subst {
+                abort!(span, "command substitution is not supported in redirection");
+            }
+
+            if let RedirectFd::StdinData { newline } = redirect {
+                self.args.push(ParseArg::RedirectData(
+                    quote!(#last_arg_str.into_bytes()),
+                    newline,
+                ));
+                self.last_arg_str = TokenStream::new();
+                return self.add_arg_with_token(token, token_span);
+            }
+
+            if let RedirectFd::FdIn { fd, rw } = redirect {
+                self.args
+                    .push(ParseArg::RedirectFileIn(fd, quote!(#last_arg_str), rw));
+                self.last_arg_str = TokenStream::new();
+                return self.add_arg_with_token(token, token_span);
+            }
 
             let mut stdouterr = false;
             let (fd, append) = match redirect {
                 RedirectFd::Stdin => (0, false),
+                RedirectFd::StdinData { .. } | RedirectFd::FdIn { .. } => unreachable!(),
                 RedirectFd::Stdout { append } => (1, append),
                 RedirectFd::Stderr { append } => (2, append),
                 RedirectFd::StdoutErr { append } => {
                     stdouterr = true;
                     (1, append)
                 }
+                RedirectFd::FdOut { fd, append } => (fd, append),
             };
             self.args
                 .push(ParseArg::RedirectFile(fd, quote!(#last_arg_str), append));
             if stdouterr {
                 self.args.push(ParseArg::RedirectFd(2, 1));
             }
+        } else if self.last_arg_subst {
+            self.args
+                .push(ParseArg::ArgSubst(quote!(#last_arg_str), false));
         } else if !last_arg_str.is_empty() {
             self.args.push(ParseArg::ArgStr(quote!(#last_arg_str)));
         }
         let mut new_redirect = (false, false, false);
         match token {
             SepToken::Space => new_redirect = self.seen_redirect,
-            SepToken::SemiColon => self.args.push(ParseArg::Semicolon),
+            SepToken::SemiColon => {
+                // "cmds & ;" is the same as "cmds &"
+                if !matches!(self.args.last(), Some(ParseArg::Background)) {
+                    self.args.push(ParseArg::Semicolon)
+                }
+            }
+            SepToken::Background => self.args.push(ParseArg::Background),
+            SepToken::And => self.args.push(ParseArg::And),
+            SepToken::Or => self.args.push(ParseArg::Or),
             SepToken::Pipe => {
                 Self::check_set_redirect(&mut self.seen_redirect.1, "stdout", token_span);
                 self.args.push(ParseArg::Pipe);
@@ -190,6 +410,7 @@ impl Lexer {
         }
         self.seen_redirect = new_redirect;
         self.last_arg_str = TokenStream::new();
+        self.last_arg_subst = false;
     }
 
     fn extend_last_arg(&mut self, stream: TokenStream) {
@@ -199,6 +420,18 @@ impl Lexer {
         self.last_arg_str.extend(quote!(.append(#stream)));
     }
 
+    // the argument turns into `SubstArg` once any command substitution is found
+    fn extend_last_arg_subst(&mut self, stream: TokenStream) {
+        let last_arg_str = &self.last_arg_str;
+        if last_arg_str.is_empty() {
+            self.last_arg_str = quote!(::cmd_lib::SubstArg::default());
+        } else if !self.last_arg_subst {
+            self.last_arg_str = quote!(::cmd_lib::SubstArg::from(#last_arg_str));
+        }
+        self.last_arg_subst = true;
+        self.last_arg_str.extend(stream);
+    }
+
     fn check_set_redirect(redirect: &mut bool, name: &str, span: Span) {
         if *redirect {
             abort!(span, "already set {} redirection", name);
@@ -211,7 +444,9 @@ impl Lexer {
             abort!(span, "wrong double redirection format");
         }
         match fd {
-            RedirectFd::Stdin => Self::check_set_redirect(&mut self.seen_redirect.0, "stdin", span),
+            RedirectFd::Stdin | RedirectFd::StdinData { .. } => {
+                Self::check_set_redirect(&mut self.seen_redirect.0, "stdin", span)
+            }
             RedirectFd::Stdout { append: _ } => {
                 Self::check_set_redirect(&mut self.seen_redirect.1, "stdout", span)
             }
@@ -222,6 +457,12 @@ impl Lexer {
                 Self::check_set_redirect(&mut self.seen_redirect.1, "stdout", span);
                 Self::check_set_redirect(&mut self.seen_redirect.2, "stderr", span);
             }
+            RedirectFd::FdIn { fd, rw: _ } | RedirectFd::FdOut { fd, append: _ } => match fd {
+                0 => Self::check_set_redirect(&mut self.seen_redirect.0, "stdin", span),
+                1 => Self::check_set_redirect(&mut self.seen_redirect.1, "stdout", span),
+                2 => Self::check_set_redirect(&mut self.seen_redirect.2, "stderr", span),
+                _ => {}
+            },
         }
         self.last_redirect = Some((fd, span));
     }
@@ -230,15 +471,31 @@ impl Lexer {
         let s = lit.to_string();
         if s.starts_with('\"') || s.starts_with('r') {
             // string literal
-            let ss = scan_str_lit(&lit);
-            self.extend_last_arg(quote!(#ss.into_os_string()));
+            let (ss, found_subst) = scan_str_lit_with_subst(
+                &lit,
+                self.iter.gaps.is_none(),
+                self.last_redirect.is_none(),
+            );
+            if found_subst {
+                self.extend_last_arg_subst(quote!(.append_subst(#ss)));
+            } else {
+                self.extend_last_arg(quote!(#ss.into_os_string()));
+            }
         } else {
             let mut is_redirect = false;
-            if s == "1" || s == "2" {
+            if self.last_arg_str.is_empty() && s.bytes().all(|c| c.is_ascii_digit()) {
                 if let Some(TokenTree::Punct(ref p)) = self.iter.peek_no_gap() {
-                    if p.as_char() == '>' {
+                    let ch = p.as_char();
+                    if ch == '>' || ch == '<' {
+                        let fd = s
+                            .parse::<i32>()
+                            .unwrap_or_else(|_| abort!(lit.span(), "invalid fd number: {}", s));
                         self.iter.next();
-                        self.scan_redirect_out(if s == "1" { 1 } else { 2 });
+                        if ch == '>' {
+                            self.scan_redirect_out(fd);
+                        } else {
+                            self.scan_redirect_in(fd);
+                        }
                         is_redirect = true;
                     }
                 }
@@ -251,6 +508,10 @@ impl Lexer {
 
     fn scan_pipe(&mut self) {
         if let Some(TokenTree::Punct(p)) = self.iter.peek_no_gap() {
+            if p.as_char() == '|' {
+                self.iter.next();
+                return self.scan_and_or(SepToken::Or, "||");
+            }
             if p.as_char() == '&' {
                 if let Some(ref redirect) = self.last_redirect {
                     abort!(redirect.1, "invalid '&': found previous redirect");
@@ -276,14 +537,74 @@ impl Lexer {
         self.add_arg_with_token(SepToken::Pipe, self.iter.span());
     }
 
+    // "< file", "<> file", "<&N", "<&-", and for stdin, also "<< data" or "<<< word" (here-string,
+    // with newline appended)
+    fn scan_redirect_in(&mut self, fd: i32) {
+        let span = self.iter.span();
+        match self.iter.peek_no_gap() {
+            Some(TokenTree::Punct(p)) if p.as_char() == '>' => {
+                self.iter.next();
+                return self.set_redirect(span, RedirectFd::FdIn { fd, rw: true });
+            }
+            Some(TokenTree::Punct(p)) if p.as_char() == '&' => {
+                self.iter.next();
+                self.set_redirect(span, RedirectFd::FdIn { fd, rw: false });
+                return self.scan_dup_fd(fd);
+            }
+            _ => {}
+        }
+        if fd != 0 {
+            return self.set_redirect(span, RedirectFd::FdIn { fd, rw: false });
+        }
+        let mut count = 1;
+        while count < 3 {
+            match self.iter.peek_no_gap() {
+                Some(TokenTree::Punct(p)) if p.as_char() == '<' => {
+                    self.iter.next();
+                    count += 1;
+                }
+                _ => break,
+            }
+        }
+        if count == 1 {
+            self.set_redirect(span, RedirectFd::Stdin);
+        } else {
+            let newline = count == 3;
+            self.set_redirect(span, RedirectFd::StdinData { newline });
+        }
+    }
+
+    // single variable as here-string or here-doc data, which can be any `AsRef<[u8]>` type
+    fn scan_data_var(&mut self, var: &Ident) -> bool {
+        let newline = match self.last_redirect {
+            Some((RedirectFd::StdinData { newline }, _)) if self.last_arg_str.is_empty() => newline,
+            _ => return false,
+        };
+        self.iter.next();
+        if self.iter.peek_no_gap().is_some() {
+            // more parts in this word, fallback to normal string
+            self.extend_last_arg(quote!(#var.as_os_str()));
+            return true;
+        }
+        self.args.push(ParseArg::RedirectData(
+            quote!({
+                use ::cmd_lib::{StdinArg as _, StdinBytes as _};
+                (&::cmd_lib::StdinVar(&#var)).stdin_data()
+            }),
+            newline,
+        ));
+        self.last_redirect = None;
+        true
+    }
+
     fn scan_redirect_out(&mut self, fd: i32) {
         let append = self.check_append();
         self.set_redirect(
             self.iter.span(),
-            if fd == 1 {
-                RedirectFd::Stdout { append }
-            } else {
-                RedirectFd::Stderr { append }
+            match fd {
+                1 => RedirectFd::Stdout { append },
+                2 => RedirectFd::Stderr { append },
+                _ => RedirectFd::FdOut { fd, append },
             },
         );
         if let Some(TokenTree::Punct(p)) = self.iter.peek_no_gap() {
@@ -292,31 +613,104 @@ impl Lexer {
                     abort!(p.span(), "raw fd not allowed for append redirection");
                 }
                 self.iter.next();
-                if let Some(TokenTree::Literal(lit)) = self.iter.peek_no_gap() {
-                    let s = lit.to_string();
-                    if s.starts_with('\"') || s.starts_with('r') {
-                        abort!(lit.span(), "invalid literal string after &");
-                    }
-                    if &s == "1" {
-                        self.args.push(ParseArg::RedirectFd(fd, 1));
-                    } else if &s == "2" {
-                        self.args.push(ParseArg::RedirectFd(fd, 2));
-                    } else {
-                        abort!(lit.span(), "Only &1 or &2 is supported");
-                    }
-                    self.last_redirect = None;
-                    self.iter.next();
-                } else {
-                    abort!(self.iter.span(), "expect &1 or &2");
+                self.scan_dup_fd(fd);
+            }
+        }
+        if self.last_redirect.is_some() {
+            if let Some(TokenTree::Punct(p)) = self.iter.peek() {
+                if p.as_char() == '&' {
+                    self.scan_redirect_buffer(fd, append);
                 }
             }
         }
     }
 
+    // "&N" to duplicate fd N, or "&-" to close the fd
+    fn scan_dup_fd(&mut self, fd: i32) {
+        match self.iter.peek_no_gap() {
+            Some(TokenTree::Literal(lit)) => {
+                let s = lit.to_string();
+                if !s.bytes().all(|c| c.is_ascii_digit()) {
+                    abort!(lit.span(), "invalid literal after &, expect fd number");
+                }
+                let target = s
+                    .parse::<i32>()
+                    .unwrap_or_else(|_| abort!(lit.span(), "invalid fd number: {}", s));
+                self.args.push(ParseArg::RedirectFd(fd, target));
+            }
+            Some(TokenTree::Punct(p)) if p.as_char() == '-' => {
+                self.args.push(ParseArg::CloseFd(fd));
+            }
+            _ => abort!(self.iter.span(), "expect &N or &-"),
+        }
+        self.last_redirect = None;
+        self.iter.next();
+    }
+
+    // "> &mut $buf" or ">> &mut $buf", to capture stdout into a rust buffer
+    fn scan_redirect_buffer(&mut self, fd: i32, append: bool) {
+        let span = self.iter.next().unwrap().span();
+        if fd != 1 {
+            abort!(span, "only stdout can be redirected to buffer");
+        }
+        match self.iter.next() {
+            Some(TokenTree::Ident(ident)) if ident == "mut" => {}
+            _ => abort!(span, "expect &mut $buffer"),
+        }
+        match self.iter.next() {
+            Some(TokenTree::Punct(p)) if p.as_char() == '$' => {}
+            _ => abort!(span, "expect &mut $buffer"),
+        }
+        let var = match self.iter.next() {
+            Some(TokenTree::Ident(var)) => var,
+            Some(TokenTree::Group(g)) if g.delimiter() == Delimiter::Brace => {
+                let mut iter = g.stream().into_iter();
+                match (iter.next(), iter.next()) {
+                    (Some(TokenTree::Ident(var)), None) => var,
+                    _ => abort!(g.span(), "expect &mut ${buffer}"),
+                }
+            }
+            _ => abort!(span, "expect &mut $buffer"),
+        };
+        self.args
+            .push(ParseArg::RedirectBuffer(quote!(#var), append, span));
+        self.last_redirect = None;
+    }
+
+    // "&&" or "||" between commands
+    fn scan_and_or(&mut self, token: SepToken, op: &str) {
+        let span = self.iter.span();
+        if self.last_arg_str.is_empty()
+            && matches!(
+                self.args.last(),
+                None | Some(ParseArg::Attr(..))
+                    | Some(ParseArg::Semicolon)
+                    | Some(ParseArg::Pipe)
+                    | Some(ParseArg::And)
+                    | Some(ParseArg::Or)
+                    | Some(ParseArg::Background)
+            )
+        {
+            abort!(span, "expect command before '{}'", op);
+        }
+        match self.iter.peek() {
+            Some(TokenTree::Punct(np)) if matches!(np.as_char(), '|' | ';' | '&') => {
+                abort!(np.span(), "expect new command after '{}'", op);
+            }
+            None => abort!(span, "expect new command after '{}'", op),
+            _ => {}
+        }
+        self.add_arg_with_token(token, span);
+    }
+
     fn scan_ampersand(&mut self) {
         if let Some(tt) = self.iter.peek_no_gap() {
             if let TokenTree::Punct(p) = tt {
                 let span = p.span();
+                if p.as_char() == '&' {
+                    self.iter.next();
+                    return self.scan_and_or(SepToken::And, "&&");
+                }
                 if p.as_char() == '>' {
                     self.iter.next();
                     let append = self.check_append();
@@ -332,19 +726,67 @@ impl Lexer {
                 self.iter.span(),
                 "wrong redirection format: no spacing permitted before '&'"
             );
-        } else if self.iter.peek().is_some() {
-            abort!(self.iter.span(), "invalid spacing after '&'");
         } else {
-            abort!(self.iter.span(), "invalid '&' at the end");
+            self.scan_background();
+        }
+    }
+
+    // "cmds &" to run the commands in background
+    fn scan_background(&mut self) {
+        let span = self.iter.span();
+        if self.last_arg_str.is_empty()
+            && matches!(
+                self.args.last(),
+                None | Some(ParseArg::Attr(..))
+                    | Some(ParseArg::Semicolon)
+                    | Some(ParseArg::Pipe)
+                    | Some(ParseArg::And)
+                    | Some(ParseArg::Or)
+                    | Some(ParseArg::Background)
+            )
+        {
+            abort!(span, "expect command before '&'");
+        }
+        if let Some(TokenTree::Punct(np)) = self.iter.peek() {
+            if matches!(np.as_char(), '|' | '&') {
+                abort!(np.span(), "expect new command after '&'");
+            }
         }
+        self.add_arg_with_token(SepToken::Background, span);
     }
 
     fn scan_dollar(&mut self) {
         let peek_no_gap = self.iter.peek_no_gap().map(|tt| tt.to_owned());
         // let peek_no_gap = None;
         if let Some(TokenTree::Ident(var)) = peek_no_gap {
+            if self.scan_data_var(&var) {
+                return;
+            }
             self.extend_last_arg(quote!(#var.as_os_str()));
         } else if let Some(TokenTree::Group(g)) = peek_no_gap {
+            if g.delimiter() == Delimiter::Parenthesis {
+                // "$(cmds)" as (part of) a single argument
+                let cmds = self.scan_subst_cmds(&g);
+                self.extend_last_arg_subst(quote!(.append_cmds(#cmds)));
+                self.iter.next();
+                return;
+            }
+            if let Some(subst) = Self::subst_in_bracket(&g) {
+                // "$[(cmds)]" for the output split into multiple arguments
+                if !self.last_arg_str.is_empty() {
+                    abort!(
+                        g.span(),
+                        "split command substitution can only be used alone"
+                    );
+                }
+                let cmds = self.scan_subst_cmds(&subst);
+                self.args.push(ParseArg::ArgSubst(
+                    quote!(::cmd_lib::SubstArg::default().append_cmds(#cmds)),
+                    true,
+                ));
+                self.iter.next();
+                return;
+            }
             if g.delimiter() != Delimiter::Brace && g.delimiter() != Delimiter::Bracket {
                 abort!(
                     g.span(),
@@ -352,6 +794,16 @@ impl Lexer {
                     format!("{:?}", g.delimiter()).to_lowercase()
                 );
             }
+            if let Some(name) = Self::env_in_brace(&g) {
+                // redirection targets are not resolved when spawning, so they read it right away
+                if self.last_redirect.is_some() {
+                    self.extend_last_arg(quote!(::cmd_lib::CmdString::default().append_env(#name)));
+                } else {
+                    self.extend_last_arg_subst(quote!(.append_env(#name)));
+                }
+                self.iter.next();
+                return;
+            }
             let mut found_var = false;
             for tt in g.stream() {
                 let span = tt.span();
@@ -360,6 +812,9 @@ impl Lexer {
                         abort!(span, "more than one variable in grouping");
                     }
                     if g.delimiter() == Delimiter::Brace {
+                        if g.stream().into_iter().count() == 1 && self.scan_data_var(var) {
+                            return;
+                        }
                         self.extend_last_arg(quote!(#var.as_os_str()));
                     } else {
                         if !self.last_arg_str.is_empty() {
@@ -378,6 +833,201 @@ impl Lexer {
         self.iter.next();
     }
 
+    // "${env.NAME}" for environment variable, instead of rust variable
+    fn env_in_brace(g: &Group) -> Option<String> {
+        if g.delimiter() != Delimiter::Brace {
+            return None;
+        }
+        let tokens: Vec<TokenTree> = g.stream().into_iter().collect();
+        match &tokens[..] {
+            [TokenTree::Ident(env), TokenTree::Punct(dot), TokenTree::Ident(name)]
+                if env == "env" && dot.as_char() == '.' =>
+            {
+                Some(name.to_string())
+            }
+            _ => None,
+        }
+    }
+
+    // "~" is only expanded when followed by "/", a user name or the end of the argument
+    fn is_tilde_end(&mut self) -> bool {
+        match self.iter.peek_no_gap() {
+            None => true,
+            Some(TokenTree::Punct(p)) => matches!(p.as_char(), '/' | ';' | '|' | '&' | '>' | '<'),
+            Some(TokenTree::Ident(_)) => true,
+            _ => false,
+        }
+    }
+
+    // "~" or "~user" at the beginning of an argument, for home directory
+    fn scan_tilde(&mut self) {
+        let mut user = String::new();
+        if let Some(TokenTree::Ident(name)) = self.iter.peek_no_gap() {
+            user = name.to_string();
+            self.iter.next();
+        }
+        self.extend_last_arg(quote!(::cmd_lib::CmdString::default().append_home(#user)));
+    }
+
+    // unquoted "[...]" is a glob pattern, like "[a-z]" or "[!0-9]"
+    fn scan_bracket_pattern(&mut self, g: Group) {
+        let inner: String = g.stream().into_iter().map(|tt| tt.to_string()).collect();
+        let s = format!("[{}]", inner);
+        self.extend_last_arg_subst(quote!(.append_pattern(#s)));
+    }
+
+    fn subst_in_bracket(g: &Group) -> Option<Group> {
+        if g.delimiter() != Delimiter::Bracket {
+            return None;
+        }
+        let mut iter = g.stream().into_iter();
+        match (iter.next(), iter.next()) {
+            (Some(TokenTree::Group(subst)), None)
+                if subst.delimiter() == Delimiter::Parenthesis =>
+            {
+                Some(subst)
+            }
+            _ => None,
+        }
+    }
+
+    fn scan_subst_cmds(&self, g: &Group) -> TokenStream {
+        self.check_nested_group(g);
+        if g.stream().is_empty() {
+            abort!(g.span(), "empty command substitution");
+        }
+        Lexer::new(g.stream()).scan().parse(false)
+    }
+
+    fn is_cmd_start(&self) -> bool {
+        self.last_arg_str.is_empty()
+            && self.last_redirect.is_none()
+            && matches!(
+                self.args.last(),
+                None | Some(ParseArg::Attr(..))
+                    | Some(ParseArg::Semicolon)
+                    | Some(ParseArg::Pipe)
+                    | Some(ParseArg::And)
+                    | Some(ParseArg::Or)
+                    | Some(ParseArg::Background)
+            )
+    }
+
+    // "!" before the command to negate its result
+    fn is_negate(&mut self) -> bool {
+        self.iter.peek_no_gap().is_none()
+            && (self.is_cmd_start()
+                || (self.last_arg_str.is_empty()
+                    && matches!(self.args.last(), Some(ParseArg::Negate(_)))))
+    }
+
+    // "(cmds)" to run the commands in a subshell
+    fn scan_subshell(&mut self, g: Group) {
+        self.check_nested_group(&g);
+        if g.stream().is_empty() {
+            abort!(g.span(), "empty subshell");
+        }
+        if let Some(tt) = self.iter.peek_no_gap() {
+            if !matches!(tt, TokenTree::Punct(p) if matches!(p.as_char(), ';' | '|' | '&' | '<' | '>'))
+            {
+                abort!(tt.span(), "subshell must be a separate command");
+            }
+        }
+        let cmds = Lexer::new(g.stream()).scan().parse(false);
+        self.args.push(ParseArg::Subshell(cmds));
+    }
+
+    // "<(cmds)" or ">(cmds)" as a standalone argument
+    fn is_proc_subst(&mut self) -> bool {
+        if !self.last_arg_str.is_empty() || self.last_redirect.is_some() {
+            return false;
+        }
+        matches!(self.iter.peek_no_gap(), Some(TokenTree::Group(g)) if g.delimiter() == Delimiter::Parenthesis)
+    }
+
+    fn scan_proc_subst(&mut self, input: bool) {
+        let g = match self.iter.next() {
+            Some(TokenTree::Group(g)) => g,
+            _ => unreachable!(),
+        };
+        match self.iter.peek_no_gap() {
+            None => {}
+            Some(TokenTree::Punct(p)) if matches!(p.as_char(), ';' | '|' | '&') => {}
+            Some(tt) => abort!(
+                tt.span(),
+                "process substitution must be a separate argument"
+            ),
+        }
+        if g.stream().is_empty() {
+            abort!(g.span(), "empty process substitution");
+        }
+        self.check_nested_group(&g);
+        let cmds = Lexer::new(g.stream()).scan().parse(true);
+        self.args.push(ParseArg::ArgProcSubst(cmds, input));
+    }
+
+    // attributes are only allowed at the beginning, like #[timeout(...)]
+    // attributes are at the start of the macro call, or of the pipelines for the env ones
+    fn is_attribute(&mut self) -> bool {
+        if !self.last_arg_str.is_empty()
+            || self.last_redirect.is_some()
+            || !matches!(
+                self.args.last(),
+                None | Some(ParseArg::Attr(..))
+                    | Some(ParseArg::Semicolon)
+                    | Some(ParseArg::And)
+                    | Some(ParseArg::Or)
+                    | Some(ParseArg::Background)
+            )
+        {
+            return false;
+        }
+        matches!(self.iter.peek_no_gap(), Some(TokenTree::Group(g)) if g.delimiter() == Delimiter::Bracket)
+    }
+
+    fn scan_attribute(&mut self) {
+        let g = match self.iter.next() {
+            Some(TokenTree::Group(g)) => g,
+            _ => unreachable!(),
+        };
+        let mut iter = g.stream().into_iter();
+        let name = match iter.next() {
+            Some(TokenTree::Ident(name)) => name,
+            _ => abort!(g.span(), "expect attribute name"),
+        };
+        let need_value = match ATTRIBUTES.iter().find(|(attr, _)| name == attr) {
+            Some((_, need_value)) => *need_value,
+            None => abort!(name.span(), "unknown attribute: {}", name),
+        };
+        let for_group = self
+            .args
+            .iter()
+            .all(|arg| matches!(arg, ParseArg::Attr(..)));
+        if !for_group && !PIPELINE_ATTRIBUTES.iter().any(|attr| name == attr) {
+            abort!(
+                name.span(),
+                "attribute {} is only allowed at the start of the macro call",
+                name
+            );
+        }
+        let value = match iter.next() {
+            Some(TokenTree::Group(v)) if v.delimiter() == Delimiter::Parenthesis => v.stream(),
+            Some(tt) => abort!(tt.span(), "expect attribute value in parentheses"),
+            None => TokenStream::new(),
+        };
+        if let Some(tt) = iter.next() {
+            abort!(tt.span(), "extra tokens in attribute");
+        }
+        if need_value == value.is_empty() {
+            if need_value {
+                abort!(name.span(), "attribute {} needs a value", name);
+            } else {
+                abort!(name.span(), "attribute {} takes no value", name);
+            }
+        }
+        self.args.push(ParseArg::Attr(name, value));
+    }
+
     fn check_append(&mut self) -> bool {
         let mut append = false;
         if let Some(TokenTree::Punct(p)) = self.iter.peek_no_gap() {
@@ -393,12 +1043,16 @@ impl Lexer {
 struct TokenStreamPeekable<I: Iterator<Item = TokenTree>> {
     peekable: Peekable<I>,
     span: Span,
+    gaps: Option<VecDeque<bool>>, // whether there are spaces before the tokens, if known
 }
 
 impl<I: Iterator<Item = TokenTree>> Iterator for TokenStreamPeekable<I> {
     type Item = I::Item;
     fn next(&mut self) -> Option<TokenTree> {
         if let Some(tt) = self.peekable.next() {
+            if let Some(gaps) = self.gaps.as_mut() {
+                gaps.pop_front();
+            }
             self.span = tt.span();
             Some(tt)
         } else {
@@ -414,6 +1068,12 @@ impl<I: Iterator<Item = TokenTree>> TokenStreamPeekable<I> {
 
     // peek next token which has no spaces between
     fn peek_no_gap(&mut self) -> Option<&TokenTree> {
+        if let Some(gaps) = self.gaps.as_ref() {
+            return match gaps.front() {
+                Some(false) => self.peekable.peek(),
+                _ => None,
+            };
+        }
         match self.peekable.peek() {
             None => None,
             Some(item) => {
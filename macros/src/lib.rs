@@ -86,7 +86,8 @@ pub fn use_custom_cmd(item: proc_macro::TokenStream) -> proc_macro::TokenStream
 /// use_builtin_cmd!(info); // import only one builtin command
 /// use_builtin_cmd!(echo, info, warn, err, die, cat); // import all the builtins
 /// ```
-/// `cd` builtin command is always enabled without importing it.
+/// `cd` builtin command is always enabled without importing it. Unlike `cmd_die!`, the `die`
+/// builtin command does not exit the process, but fails with exit code 1.
 #[proc_macro]
 #[proc_macro_error]
 pub fn use_builtin_cmd(item: proc_macro::TokenStream) -> proc_macro::TokenStream {
@@ -168,6 +169,76 @@ pub fn run_fun(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
     .into()
 }
 
+/// Run commands, returning the output as raw bytes, exactly as the last command wrote it
+/// ```
+/// # use cmd_lib::run_fun_bytes;
+/// let data = run_fun_bytes!(printf "\\x00\\xff\\n")?;
+/// assert_eq!(data, b"\x00\xff\n");
+/// # Ok::<(), std::io::Error>(())
+/// ```
+#[proc_macro]
+#[proc_macro_error]
+pub fn run_fun_bytes(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let cmds = lexer::Lexer::new(input.into()).scan().parse(false);
+    quote! ({
+        use ::cmd_lib::AsOsStr;
+        #cmds.run_fun_bytes()
+    })
+    .into()
+}
+
+/// Run commands, returning the stdout, the stderr and the exit status of the last pipeline
+/// ```
+/// # use cmd_lib::run_fun_full;
+/// let output = run_fun_full!(ls /nofile)?;
+/// assert!(!output.status.success());
+/// assert!(output.stdout.is_empty());
+/// assert!(!output.stderr.is_empty());
+/// # Ok::<(), std::io::Error>(())
+/// ```
+/// The stderr is captured instead of being logged, and failing commands are not treated as errors.
+#[proc_macro]
+#[proc_macro_error]
+pub fn run_fun_full(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let cmds = lexer::Lexer::new(input.into())
+        .scan()
+        .reject_buffer()
+        .parse(false);
+    quote! ({
+        use ::cmd_lib::AsOsStr;
+        #cmds.run_fun_full()
+    })
+    .into()
+}
+
+/// Run commands, returning the exit status of each command in the last pipeline, like
+/// `PIPESTATUS` in bash
+/// ```
+/// # use cmd_lib::run_statuses;
+/// let statuses = run_statuses!(echo "rust" | grep -q go | head -1)?;
+/// assert!(statuses[0].success());
+/// assert_eq!(statuses[1].code(), Some(1));
+/// assert!(statuses[2].success());
+/// # Ok::<(), std::io::Error>(())
+/// ```
+/// Failing commands are not treated as errors, and the result is only `Err(...)` when some
+/// command could not be spawned, or when the previous commands in a group failed. The statuses
+/// can not be negated, so `!` is not allowed in the last pipeline.
+#[proc_macro]
+#[proc_macro_error]
+pub fn run_statuses(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let cmds = lexer::Lexer::new(input.into())
+        .scan()
+        .reject_negate()
+        .reject_buffer()
+        .parse(false);
+    quote! ({
+        use ::cmd_lib::AsOsStr;
+        #cmds.run_statuses()
+    })
+    .into()
+}
+
 /// Run commands with/without pipes as a child process, returning a handle to check the final
 /// result
 /// ```
@@ -182,7 +253,10 @@ pub fn run_fun(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
 #[proc_macro]
 #[proc_macro_error]
 pub fn spawn(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
-    let cmds = lexer::Lexer::new(input.into()).scan().parse(true);
+    let cmds = lexer::Lexer::new(input.into())
+        .scan()
+        .reject_buffer()
+        .parse(true);
     quote! ({
         use ::cmd_lib::AsOsStr;
         #cmds.spawn(false)
@@ -212,7 +286,10 @@ pub fn spawn(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
 #[proc_macro]
 #[proc_macro_error]
 pub fn spawn_with_output(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
-    let cmds = lexer::Lexer::new(input.into()).scan().parse(true);
+    let cmds = lexer::Lexer::new(input.into())
+        .scan()
+        .reject_buffer()
+        .parse(true);
     quote! ({
         use ::cmd_lib::AsOsStr;
         #cmds.spawn_with_output()
@@ -1,15 +1,29 @@
-use proc_macro2::TokenStream;
+use proc_macro2::{Ident, Span, TokenStream};
+use proc_macro_error::abort;
 use quote::quote;
 use std::iter::Peekable;
+use std::vec;
 
 #[derive(Debug)]
 pub enum ParseArg {
+    Attr(Ident, TokenStream), // name, value
     Pipe,
     Semicolon,
-    RedirectFd(i32, i32),                 // fd1, fd2
-    RedirectFile(i32, TokenStream, bool), // fd1, file, append?
+    And,
+    Or,
+    Background,
+    RedirectFd(i32, i32),                   // fd1, fd2
+    RedirectFile(i32, TokenStream, bool),   // fd1, file, append?
+    RedirectFileIn(i32, TokenStream, bool), // fd1, file, read-write?
+    CloseFd(i32),
+    RedirectData(TokenStream, bool),         // data, newline?
+    RedirectBuffer(TokenStream, bool, Span), // buffer, append?, span
     ArgStr(TokenStream),
     ArgVec(TokenStream),
+    ArgProcSubst(TokenStream, bool), // cmds, input?
+    ArgSubst(TokenStream, bool),     // argument, split?
+    Subshell(TokenStream),
+    Negate(Span),
 }
 
 pub struct Parser<I: Iterator<Item = ParseArg>> {
@@ -21,38 +35,94 @@ impl<I: Iterator<Item = ParseArg>> Parser<I> {
         Self { iter }
     }
 
+    // "!" could not be applied to the statuses of the last commands, which are returned as they
+    // are
+    pub fn reject_negate(self) -> Parser<vec::IntoIter<ParseArg>> {
+        let args: Vec<_> = self.iter.collect();
+        for arg in last_cmds(&args) {
+            if let ParseArg::Negate(span) = arg {
+                abort!(span, "'!' is not supported for the exit statuses");
+            }
+        }
+        Parser::from(args.into_iter().peekable())
+    }
+
+    // the output of the last commands is returned by the macro, or by the spawned children,
+    // instead of being written to a buffer
+    pub fn reject_buffer(self) -> Parser<vec::IntoIter<ParseArg>> {
+        let args: Vec<_> = self.iter.collect();
+        for arg in last_cmds(&args) {
+            if let ParseArg::RedirectBuffer(_, _, span) = arg {
+                abort!(span, "redirecting to buffer is not supported here");
+            }
+        }
+        Parser::from(args.into_iter().peekable())
+    }
+
     pub fn parse(mut self, for_spawn: bool) -> TokenStream {
         let mut ret = quote!(::cmd_lib::GroupCmds::default());
+        while let Some(ParseArg::Attr(name, value)) = self.iter.peek() {
+            ret.extend(quote!(.#name(#value)));
+            self.iter.next();
+        }
+        let mut connector = quote!(append);
         while self.iter.peek().is_some() {
-            let cmd = self.parse_cmd();
+            let (mut cmd, sep) = self.parse_cmd();
+            if matches!(sep, Some(ParseArg::Background)) {
+                cmd.extend(quote!(.background()));
+            }
             if !cmd.is_empty() {
-                ret.extend(quote!(.append(#cmd)));
+                ret.extend(quote!(.#connector(#cmd)));
                 assert!(
                     !(for_spawn && self.iter.peek().is_some()),
                     "wrong spawning format: group command not allowed"
                 );
             }
+            connector = match sep {
+                Some(ParseArg::And) => quote!(and),
+                Some(ParseArg::Or) => quote!(or),
+                _ => quote!(append),
+            };
         }
         ret
     }
 
-    fn parse_cmd(&mut self) -> TokenStream {
+    // returns the commands, and the separator after them
+    fn parse_cmd(&mut self) -> (TokenStream, Option<ParseArg>) {
         let mut cmds = quote!(::cmd_lib::Cmds::default());
+        while let Some(ParseArg::Attr(name, value)) = self.iter.peek() {
+            cmds.extend(quote!(.#name(#value)));
+            self.iter.next();
+        }
+        let mut buffer = TokenStream::new();
         while self.iter.peek().is_some() {
-            let cmd = self.parse_pipe();
+            let cmd = self.parse_pipe(&mut buffer);
             cmds.extend(quote!(.pipe(#cmd)));
             if !matches!(self.iter.peek(), Some(ParseArg::Pipe)) {
-                self.iter.next();
-                break;
+                cmds.extend(buffer);
+                return (cmds, self.iter.next());
             }
             self.iter.next();
         }
-        cmds
+        cmds.extend(buffer);
+        (cmds, None)
     }
 
-    fn parse_pipe(&mut self) -> TokenStream {
+    fn parse_pipe(&mut self, buffer: &mut TokenStream) -> TokenStream {
         let mut ret = quote!(::cmd_lib::Cmd::default());
+        let mut subshell = false;
         while let Some(arg) = self.iter.peek() {
+            if subshell
+                && matches!(
+                    arg,
+                    ParseArg::ArgStr(_)
+                        | ParseArg::ArgVec(_)
+                        | ParseArg::ArgSubst(..)
+                        | ParseArg::ArgProcSubst(..)
+                )
+            {
+                panic!("unexpected argument after subshell");
+            }
             match arg {
                 ParseArg::RedirectFd(fd1, fd2) => {
                     if fd1 != fd2 {
@@ -60,7 +130,7 @@ impl<I: Iterator<Item = ParseArg>> Parser<I> {
                         match (fd1, fd2) {
                             (1, 2) => redirect.extend(quote!(::StdoutToStderr)),
                             (2, 1) => redirect.extend(quote!(::StderrToStdout)),
-                            _ => panic!("unsupported fd numbers: {} {}", fd1, fd2),
+                            _ => redirect.extend(quote!(::FdToFd(#fd1, #fd2))),
                         }
                         ret.extend(quote!(.add_redirect(#redirect)));
                     }
@@ -75,20 +145,75 @@ impl<I: Iterator<Item = ParseArg>> Parser<I> {
                         2 => {
                             redirect.extend(quote!(::StderrToFile(#file.into_path_buf(), #append)))
                         }
-                        _ => panic!("unsupported fd ({}) redirect to file {}", fd1, file),
+                        _ => redirect
+                            .extend(quote!(::FdToFile(#fd1, #file.into_path_buf(), #append))),
                     }
                     ret.extend(quote!(.add_redirect(#redirect)));
                 }
+                ParseArg::RedirectFileIn(fd, file, rw) => {
+                    let redirect = if *fd == 0 && !*rw {
+                        quote!(::cmd_lib::Redirect::FileToStdin(#file.into_path_buf()))
+                    } else {
+                        quote!(::cmd_lib::Redirect::FileToFd(#fd, #file.into_path_buf(), #rw))
+                    };
+                    ret.extend(quote!(.add_redirect(#redirect)));
+                }
+                ParseArg::CloseFd(fd) => {
+                    ret.extend(quote!(.add_redirect(::cmd_lib::Redirect::CloseFd(#fd))));
+                }
+                ParseArg::RedirectData(data, newline) => {
+                    let data = if *newline {
+                        quote!([#data, vec![b'\n']].concat())
+                    } else {
+                        quote!(#data)
+                    };
+                    ret.extend(quote!(.add_redirect(::cmd_lib::Redirect::DataToStdin(#data))));
+                }
+                ParseArg::RedirectBuffer(var, append, _) => {
+                    *buffer = quote!(.stdout_to_buffer(&mut #var, #append));
+                }
+                ParseArg::Negate(_) => {
+                    ret.extend(quote!(.add_arg("!")));
+                }
                 ParseArg::ArgStr(opt) => {
                     ret.extend(quote!(.add_arg(#opt)));
                 }
                 ParseArg::ArgVec(opts) => {
                     ret.extend(quote! (.add_args(#opts)));
                 }
-                ParseArg::Pipe | ParseArg::Semicolon => break,
+                ParseArg::Subshell(cmds) => {
+                    ret.extend(quote!(.subshell(#cmds)));
+                    subshell = true;
+                }
+                ParseArg::ArgSubst(arg, split) => {
+                    ret.extend(quote!(.add_subst_arg(#arg, #split)));
+                }
+                ParseArg::ArgProcSubst(cmds, input) => {
+                    ret.extend(quote!(.add_proc_subst(#cmds, #input)));
+                }
+                ParseArg::Pipe
+                | ParseArg::Semicolon
+                | ParseArg::And
+                | ParseArg::Or
+                | ParseArg::Background => break,
+                ParseArg::Attr(name, _) => panic!("unexpected attribute: {}", name),
             }
             self.iter.next();
         }
         ret
     }
 }
+
+// arguments of the last commands in the group, which are run by the macro itself
+fn last_cmds(args: &[ParseArg]) -> &[ParseArg] {
+    let start = args[..args.len().saturating_sub(1)]
+        .iter()
+        .rposition(|arg| {
+            matches!(
+                arg,
+                ParseArg::Semicolon | ParseArg::And | ParseArg::Or | ParseArg::Background
+            )
+        })
+        .map_or(0, |pos| pos + 1);
+    &args[start..]
+}
@@ -12,7 +12,7 @@ pub fn builtin_echo(env: &mut CmdEnv) -> CmdResult {
 #[doc(hidden)]
 pub fn builtin_die(env: &mut CmdEnv) -> CmdResult {
     error!("FATAL: {}", env.args()[1..].join(" "));
-    std::process::exit(1);
+    env.exit(1)
 }
 
 #[doc(hidden)]
@@ -54,7 +54,7 @@ pub fn builtin_cat(env: &mut CmdEnv) -> CmdResult {
         return Ok(());
     }
 
-    let mut file = PathBuf::from(env.args()[1].to_owned());
+    let mut file = PathBuf::from(&env.args_os()[1]);
     if file.is_relative() {
         file = PathBuf::from(env.current_dir()).join(file);
     }
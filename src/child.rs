@@ -1,9 +1,26 @@
-use crate::{process, CmdResult, FunResult};
-use log::{info, warn};
-use os_pipe::PipeReader;
-use std::io::{BufRead, BufReader, Error, ErrorKind, Read, Result};
-use std::process::{Child, ExitStatus};
-use std::thread::JoinHandle;
+use crate::error::ExitCode;
+use crate::{process, CmdError, CmdResult, FunResult, StderrPolicy};
+use log::{log, warn};
+use os_pipe::{PipeReader, PipeWriter};
+use std::collections::VecDeque;
+use std::ffi::OsString;
+use std::io::{self, BufRead, BufReader, Error, ErrorKind, Read, Result};
+use std::os::unix::ffi::OsStringExt;
+use std::os::unix::process::ExitStatusExt;
+use std::path::{Path, PathBuf};
+use std::process::{Child, ExitStatus, Output};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+// max number of stderr lines kept in the errors
+const STDERR_TAIL_LINES: usize = 10;
+// default time to wait after SIGTERM, before sending SIGKILL to the timed out children
+const DEFAULT_KILL_GRACE: Duration = Duration::from_secs(5);
+// interval to check whether the subshell running the children is cancelled
+const CANCEL_CHECK_INTERVAL: Duration = Duration::from_millis(10);
 
 /// Representation of running or exited children processes, connected with pipes
 /// optionally.
@@ -11,25 +28,111 @@ use std::thread::JoinHandle;
 /// Calling `spawn!` macro will return `Result<CmdChildren>`
 pub struct CmdChildren {
     children: Vec<Result<CmdChild>>,
+    // children of the process substitutions, which are not part of the pipeline
+    proc_substs: Vec<Result<CmdChild>>,
     ignore_error: bool,
+    deadline: Option<Deadline>,
+    kill_grace: Duration,
+    stdin: Option<PipeWriter>,
+    // full commands, if the result is negated with "!"
+    negate: Option<String>,
+    // cancel flag of the subshell running the children
+    cancelled: Option<Arc<AtomicBool>>,
 }
 
 impl CmdChildren {
-    pub(crate) fn new(children: Vec<Result<CmdChild>>, ignore_error: bool) -> Self {
+    pub(crate) fn new(
+        children: Vec<Result<CmdChild>>,
+        proc_substs: Vec<Result<CmdChild>>,
+        ignore_error: bool,
+    ) -> Self {
         Self {
             children,
+            proc_substs,
             ignore_error,
+            deadline: None,
+            kill_grace: DEFAULT_KILL_GRACE,
+            stdin: None,
+            negate: None,
+            cancelled: None,
+        }
+    }
+
+    // all the children, including the ones of the process substitutions
+    pub(crate) fn into_children(self) -> Vec<Result<CmdChild>> {
+        let mut children = self.proc_substs;
+        children.extend(self.children);
+        children
+    }
+
+    pub(crate) fn set_stdin(&mut self, stdin: Option<PipeWriter>) {
+        self.stdin = stdin;
+    }
+
+    pub(crate) fn set_negate(&mut self, full_cmds: &str) {
+        self.negate = Some(full_cmds.to_string());
+    }
+
+    pub(crate) fn set_cancelled(&mut self, cancelled: Option<Arc<AtomicBool>>) {
+        self.cancelled = cancelled;
+    }
+
+    pub(crate) fn set_timeout(&mut self, deadline: Option<Deadline>, kill_grace: Option<Duration>) {
+        self.deadline = deadline;
+        if let Some(kill_grace) = kill_grace {
+            self.kill_grace = kill_grace;
         }
     }
 
     pub(crate) fn into_fun_children(self) -> FunChildren {
         FunChildren {
             children: self.children,
+            proc_substs: self.proc_substs,
             ignore_error: self.ignore_error,
+            deadline: self.deadline,
+            kill_grace: self.kill_grace,
+            stdin: self.stdin,
+            negate: self.negate,
+            cancelled: self.cancelled,
         }
     }
 
+    /// Takes the handle writing to the stdin of the first command, if it was spawned with
+    /// `#[piped_stdin]` attribute
+    ///
+    /// The handle should be dropped to close the stdin, when all the input is written.
+    /// Otherwise it will be closed before waiting for the children.
+    pub fn take_stdin(&mut self) -> Option<PipeWriter> {
+        self.stdin.take()
+    }
+
     pub fn wait(&mut self) -> CmdResult {
+        self.stdin = None;
+        let watchdog = Watchdog::start(
+            &self.children,
+            &self.proc_substs,
+            self.deadline,
+            self.kill_grace,
+            &self.cancelled,
+        );
+        let ret = self.wait_all();
+        let ret = ret.and(Self::wait_children(&mut self.proc_substs));
+        let ret = negate_result(self.negate.as_deref(), ret);
+        Watchdog::stop(watchdog, ret)
+    }
+
+    /// Waits for the children to exit, killing all of them if they are still running after
+    /// `timeout`
+    ///
+    /// The commands will receive `SIGTERM` at first, and then `SIGKILL` if they are still
+    /// running after the grace period (5 seconds by default, which can be changed by the
+    /// `#[kill_grace(...)]` macro attribute). The returned error is of `ErrorKind::TimedOut`.
+    pub fn wait_timeout(&mut self, timeout: Duration) -> CmdResult {
+        self.deadline = Some(Deadline::new(timeout));
+        self.wait()
+    }
+
+    fn wait_all(&mut self) -> CmdResult {
         // wait for the last child result
         let handle = self.children.pop().unwrap();
         match handle {
@@ -47,6 +150,101 @@ impl CmdChildren {
         Self::wait_children(&mut self.children)
     }
 
+    /// Waits for all the children to exit, returning the exit status of each command in the
+    /// pipeline, the same as `PIPESTATUS` in bash
+    ///
+    /// A failing command is not treated as an error here, and builtin or custom commands
+    /// returning errors are reported with exit code 1. The statuses are not negated by `!`.
+    pub fn wait_statuses(&mut self) -> Result<Vec<ExitStatus>> {
+        self.stdin = None;
+        let watchdog = Watchdog::start(
+            &self.children,
+            &self.proc_substs,
+            self.deadline,
+            self.kill_grace,
+            &self.cancelled,
+        );
+        let ret = Self::wait_children_statuses(&mut self.children);
+        let _ = Self::wait_children(&mut self.proc_substs);
+        Watchdog::stop(watchdog, ret)
+    }
+
+    fn wait_children_statuses(children: &mut Vec<Result<CmdChild>>) -> Result<Vec<ExitStatus>> {
+        // the stderr of all the commands is read in background, and the output of the last one
+        // is drained first, so none of them would be blocked by a full pipe
+        for child in children.iter_mut().flatten() {
+            child.start_stderr_logging();
+        }
+        let mut statuses = vec![];
+        let mut ret = Ok(());
+        for child in children.drain(..).rev() {
+            match child.and_then(CmdChild::wait_status) {
+                Ok(status) => statuses.push(status),
+                Err(e) => ret = Err(e),
+            }
+        }
+        statuses.reverse();
+        ret.map(|_| statuses)
+    }
+
+    /// Returns the process ids of the children, not including builtin or custom commands, or
+    /// process substitutions
+    pub fn pids(&self) -> Vec<u32> {
+        Self::children_pids(&self.children)
+    }
+
+    /// Checks whether all the children have exited, without blocking
+    ///
+    /// After it returns `Ok(true)`, the final result can be collected by waiting the children
+    /// without blocking.
+    pub fn try_wait(&mut self) -> Result<bool> {
+        Ok(Self::try_wait_children(&mut self.children)?
+            && Self::try_wait_children(&mut self.proc_substs)?)
+    }
+
+    /// Kills all the children with `SIGKILL`, and asks the builtin or custom commands to cancel
+    pub fn kill(&mut self) -> CmdResult {
+        self.signal(libc::SIGKILL)
+    }
+
+    /// Sends signal `sig` to all the children
+    ///
+    /// Builtin or custom commands can not receive signals, and they are asked to cancel instead
+    /// (see [`CmdEnv::is_cancelled`](crate::CmdEnv::is_cancelled)) when `sig` is `SIGINT`,
+    /// `SIGTERM` or `SIGKILL`.
+    pub fn signal(&mut self, sig: i32) -> CmdResult {
+        let ret = Self::signal_children(&mut self.children, sig);
+        ret.and(Self::signal_children(&mut self.proc_substs, sig))
+    }
+
+    fn children_pids(children: &[Result<CmdChild>]) -> Vec<u32> {
+        children
+            .iter()
+            .filter_map(|child| child.as_ref().ok().and_then(CmdChild::pid))
+            .collect()
+    }
+
+    fn try_wait_children(children: &mut [Result<CmdChild>]) -> Result<bool> {
+        for child in children.iter_mut().flatten() {
+            if !child.try_wait()? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    fn signal_children(children: &mut [Result<CmdChild>], sig: i32) -> CmdResult {
+        let mut ret = Ok(());
+        for child in children.iter_mut().flatten() {
+            if let Err(e) = child.signal(sig) {
+                if ret.is_ok() {
+                    ret = Err(e);
+                }
+            }
+        }
+        ret
+    }
+
     fn wait_children(children: &mut Vec<Result<CmdChild>>) -> CmdResult {
         let mut ret = Ok(());
         while !children.is_empty() {
@@ -70,11 +268,75 @@ impl CmdChildren {
 /// Calling `spawn_with_output!` macro will return `Result<FunChildren>`
 pub struct FunChildren {
     children: Vec<Result<CmdChild>>,
+    proc_substs: Vec<Result<CmdChild>>,
     ignore_error: bool,
+    deadline: Option<Deadline>,
+    kill_grace: Duration,
+    stdin: Option<PipeWriter>,
+    // full commands, if the result is negated with "!"
+    negate: Option<String>,
+    // cancel flag of the subshell running the children
+    cancelled: Option<Arc<AtomicBool>>,
 }
 
 impl FunChildren {
     pub fn wait_with_output(&mut self) -> FunResult {
+        let output = self.wait_with_raw_output()?;
+        let mut s = String::from_utf8_lossy(&output).to_string();
+        if s.ends_with('\n') {
+            s.pop();
+        }
+        Ok(s)
+    }
+
+    /// Waits for the children to exit, returning the output exactly as the last command wrote it
+    ///
+    /// Unlike `wait_with_output()`, no UTF-8 decoding is done and the trailing newline is kept,
+    /// which is suitable for binary output.
+    pub fn wait_with_raw_output(&mut self) -> Result<Vec<u8>> {
+        self.stdin = None;
+        let watchdog = Watchdog::start(
+            &self.children,
+            &self.proc_substs,
+            self.deadline,
+            self.kill_grace,
+            &self.cancelled,
+        );
+        let ret = self.wait_all_with_output();
+        let ret = negate_result(self.negate.as_deref(), ret);
+        Watchdog::stop(watchdog, ret)
+    }
+
+    /// Waits for the children to exit, returning the output as `OsString` without UTF-8
+    /// decoding, with one trailing newline stripped
+    ///
+    /// It is suitable for the commands printing a path, which might not be valid UTF-8.
+    pub fn wait_with_os_output(&mut self) -> Result<OsString> {
+        let mut output = self.wait_with_raw_output()?;
+        if output.ends_with(b"\n") {
+            output.pop();
+        }
+        Ok(OsString::from_vec(output))
+    }
+
+    /// Waits for the children to exit and collects the output, killing all of them if they
+    /// are still running after `timeout`
+    ///
+    /// See [`CmdChildren::wait_timeout`] for details.
+    pub fn wait_timeout(&mut self, timeout: Duration) -> FunResult {
+        self.deadline = Some(Deadline::new(timeout));
+        self.wait_with_output()
+    }
+
+    fn wait_all_with_output(&mut self) -> Result<Vec<u8>> {
+        let ret = self.wait_pipeline_with_output();
+        match CmdChildren::wait_children(&mut self.proc_substs) {
+            Err(e) if ret.is_ok() && !self.ignore_error => Err(e),
+            _ => ret,
+        }
+    }
+
+    fn wait_pipeline_with_output(&mut self) -> Result<Vec<u8>> {
         // wait for the last child result
         let handle = self.children.pop().unwrap();
         match handle {
@@ -90,34 +352,189 @@ impl FunChildren {
                         Err(e)
                     }
                     Ok(output) => {
-                        let mut s = String::from_utf8_lossy(&output).to_string();
-                        if s.ends_with('\n') {
-                            s.pop();
-                        }
                         let ret = CmdChildren::wait_children(&mut self.children);
                         if let Err(e) = ret {
                             if !self.ignore_error {
                                 return Err(e);
                             }
                         }
-                        Ok(s)
+                        Ok(output)
                     }
                 }
             }
         }
     }
 
+    /// Waits for all the children to exit with output discarded, returning the exit status of
+    /// each command in the pipeline
+    ///
+    /// See [`CmdChildren::wait_statuses`] for details.
+    pub fn wait_statuses(&mut self) -> Result<Vec<ExitStatus>> {
+        self.stdin = None;
+        let watchdog = Watchdog::start(
+            &self.children,
+            &self.proc_substs,
+            self.deadline,
+            self.kill_grace,
+            &self.cancelled,
+        );
+        let ret = CmdChildren::wait_children_statuses(&mut self.children);
+        let _ = CmdChildren::wait_children(&mut self.proc_substs);
+        Watchdog::stop(watchdog, ret)
+    }
+
+    /// Waits for the children to exit, collecting the stdout, the stderr and the exit status
+    ///
+    /// The stderr of all the commands in the pipeline is captured instead of being logged, and
+    /// the stdout is kept as raw bytes. A command exiting with error is not treated as an error
+    /// here, and the reported status is the one of the last command, or the last failing one if
+    /// pipefail is enabled. With `!`, a successful status is reported as exit code 1, and a
+    /// failing one as exit code 0.
+    pub fn wait_with_full_output(&mut self) -> Result<Output> {
+        self.stdin = None;
+        let watchdog = Watchdog::start(
+            &self.children,
+            &self.proc_substs,
+            self.deadline,
+            self.kill_grace,
+            &self.cancelled,
+        );
+        let ret = self.wait_all_with_full_output();
+        Watchdog::stop(watchdog, ret)
+    }
+
+    fn wait_all_with_full_output(&mut self) -> Result<Output> {
+        // capture stderr of all the commands in background, so none of them would be blocked
+        let stderr_threads: Vec<_> = self
+            .children
+            .iter_mut()
+            .chain(self.proc_substs.iter_mut())
+            .flatten()
+            .filter_map(|child| child.stderr.take())
+            .map(|mut stderr| {
+                thread::spawn(move || {
+                    let mut buf = vec![];
+                    let _ = stderr.read_to_end(&mut buf);
+                    buf
+                })
+            })
+            .collect();
+
+        let mut stdout = vec![];
+        let mut ret: CmdResult = Ok(());
+        if let Some(Ok(child)) = self.children.last_mut() {
+            if let Some(mut out) = child.stdout.take() {
+                if let Err(e) = out.read_to_end(&mut stdout) {
+                    ret = Err(child.info.io_error(e, false).into());
+                }
+            }
+        }
+        let statuses = CmdChildren::wait_children_statuses(&mut self.children);
+        let _ = CmdChildren::wait_children(&mut self.proc_substs);
+
+        let mut stderr = vec![];
+        for thread in stderr_threads {
+            if let Ok(buf) = thread.join() {
+                stderr.extend(buf);
+            }
+        }
+        ret?;
+
+        let statuses = statuses?;
+        let mut status = statuses
+            .last()
+            .copied()
+            .unwrap_or_else(|| ExitStatus::from_raw(0));
+        if process::pipefail_enabled() {
+            if let Some(failed) = statuses.iter().rev().find(|status| !status.success()) {
+                status = *failed;
+            }
+        }
+        if self.negate.is_some() {
+            status = ExitStatus::from_raw(if status.success() { 1 << 8 } else { 0 });
+        }
+        Ok(Output {
+            status,
+            stdout,
+            stderr,
+        })
+    }
+
+    /// Returns the process ids of the children, see [`CmdChildren::pids`]
+    pub fn pids(&self) -> Vec<u32> {
+        CmdChildren::children_pids(&self.children)
+    }
+
+    /// Checks whether all the children have exited, see [`CmdChildren::try_wait`]
+    pub fn try_wait(&mut self) -> Result<bool> {
+        Ok(CmdChildren::try_wait_children(&mut self.children)?
+            && CmdChildren::try_wait_children(&mut self.proc_substs)?)
+    }
+
+    /// Kills all the children, see [`CmdChildren::kill`]
+    pub fn kill(&mut self) -> CmdResult {
+        self.signal(libc::SIGKILL)
+    }
+
+    /// Sends signal `sig` to all the children, see [`CmdChildren::signal`]
+    pub fn signal(&mut self, sig: i32) -> CmdResult {
+        let ret = CmdChildren::signal_children(&mut self.children, sig);
+        ret.and(CmdChildren::signal_children(&mut self.proc_substs, sig))
+    }
+
+    /// Returns an iterator over the output lines, which are read while the children are running
+    ///
+    /// After all the output is read, the children are waited, and the last item is the error if
+    /// any command failed, following the same rules as `wait_with_output()`. Dropping the iterator
+    /// early closes the output pipe and kills the remaining commands.
+    pub fn lines(mut self) -> FunLines {
+        self.stdin = None;
+        let watchdog = Watchdog::start(
+            &self.children,
+            &self.proc_substs,
+            self.deadline,
+            self.kill_grace,
+            &self.cancelled,
+        );
+        // stderr is read while streaming the output, and its tail is kept for the errors
+        for child in self
+            .children
+            .iter_mut()
+            .chain(self.proc_substs.iter_mut())
+            .flatten()
+        {
+            child.start_stderr_logging();
+        }
+        let reader = match self.children.last_mut() {
+            Some(Ok(child)) => child.stdout.take().map(BufReader::new),
+            _ => None,
+        };
+        FunLines {
+            reader,
+            children: self,
+            watchdog,
+            finished: false,
+        }
+    }
+
+    /// Takes the handle writing to the stdin of the first command, see
+    /// [`CmdChildren::take_stdin`]
+    pub fn take_stdin(&mut self) -> Option<PipeWriter> {
+        self.stdin.take()
+    }
+
     pub fn wait_with_pipe(&mut self, f: &mut dyn FnMut(Box<dyn Read>)) -> CmdResult {
+        self.stdin = None;
         let child = self.children.pop().unwrap()?;
-        let polling_stderr = StderrLogging::new(&child.cmd, child.stderr);
+        let polling_stderr = StderrLogging::new(&child.info, child.stderr, &child.stderr_policy);
         match child.handle {
-            CmdChildHandle::Proc(mut proc) => {
+            CmdChildHandle::Proc(mut proc, _) => {
                 if let Some(stdout) = child.stdout {
                     f(Box::new(stdout));
                     let _ = proc.kill();
                 }
             }
-            CmdChildHandle::Thread(_) => {
+            CmdChildHandle::Thread(..) => {
                 if let Some(stdout) = child.stdout {
                     f(Box::new(stdout));
                 }
@@ -129,34 +546,170 @@ impl FunChildren {
             }
         };
         drop(polling_stderr);
-        CmdChildren::wait_children(&mut self.children)
+        let ret = CmdChildren::wait_children(&mut self.children);
+        ret.and(CmdChildren::wait_children(&mut self.proc_substs))
+    }
+}
+
+/// Iterator over the output lines of the running children
+///
+/// Calling `lines()` on [`FunChildren`] will return `FunLines`
+pub struct FunLines {
+    reader: Option<BufReader<PipeReader>>,
+    children: FunChildren,
+    watchdog: Option<Watchdog>,
+    finished: bool,
+}
+
+impl FunLines {
+    fn finish(&mut self) -> CmdResult {
+        self.finished = true;
+        self.reader = None;
+        let ret = self.children.wait_all_with_output().map(|_| ());
+        let ret = negate_result(self.children.negate.as_deref(), ret);
+        Watchdog::stop(self.watchdog.take(), ret)
+    }
+}
+
+impl Iterator for FunLines {
+    type Item = Result<String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.finished {
+            return None;
+        }
+        if let Some(reader) = self.reader.as_mut() {
+            let mut buf = vec![];
+            match reader.read_until(b'\n', &mut buf) {
+                Ok(0) => {}
+                Ok(_) => {
+                    if buf.ends_with(b"\n") {
+                        buf.pop();
+                    }
+                    return Some(Ok(String::from_utf8_lossy(&buf).to_string()));
+                }
+                Err(e) => {
+                    // report the reading error first, and the children are waited next time
+                    self.reader = None;
+                    return Some(Err(e));
+                }
+            }
+        }
+        self.finish().err().map(Err)
+    }
+}
+
+impl Drop for FunLines {
+    fn drop(&mut self) {
+        if !self.finished {
+            // not all the output is read, the errors of the killed commands are expected
+            self.reader = None;
+            let _ = self.children.kill();
+            let _ = self.finish();
+        }
     }
 }
 
 pub(crate) struct CmdChild {
     handle: CmdChildHandle,
-    cmd: String,
+    info: CmdInfo,
     stdout: Option<PipeReader>,
     stderr: Option<PipeReader>,
+    stderr_policy: StderrPolicy,
+    stderr_logging: Option<StderrLogging>,
 }
 
 impl CmdChild {
     pub(crate) fn new(
         handle: CmdChildHandle,
-        cmd: String,
+        info: CmdInfo,
         stdout: Option<PipeReader>,
         stderr: Option<PipeReader>,
+        stderr_policy: StderrPolicy,
     ) -> Self {
         Self {
             handle,
-            cmd,
+            info,
             stdout,
             stderr,
+            stderr_policy,
+            stderr_logging: None,
+        }
+    }
+
+    // start reading stderr in background, so the command would not be blocked by a full pipe
+    fn start_stderr_logging(&mut self) {
+        if self.stderr_logging.is_none() {
+            self.stderr_logging = Some(StderrLogging::new(
+                &self.info,
+                self.stderr.take(),
+                &self.stderr_policy,
+            ));
+        }
+    }
+
+    fn take_stderr_logging(&mut self) -> StderrLogging {
+        self.start_stderr_logging();
+        self.stderr_logging.take().unwrap()
+    }
+
+    fn pid(&self) -> Option<u32> {
+        match self.handle {
+            CmdChildHandle::Proc(ref proc, _) => Some(proc.id()),
+            _ => None,
+        }
+    }
+
+    fn reaped_flag(&self) -> Option<(u32, Arc<Mutex<bool>>)> {
+        match self.handle {
+            CmdChildHandle::Proc(ref proc, ref reaped) => Some((proc.id(), reaped.clone())),
+            _ => None,
+        }
+    }
+
+    fn cancel_flag(&self) -> Option<Arc<AtomicBool>> {
+        match self.handle {
+            CmdChildHandle::Thread(_, ref cancelled) => Some(cancelled.clone()),
+            _ => None,
+        }
+    }
+
+    fn try_wait(&mut self) -> Result<bool> {
+        match self.handle {
+            CmdChildHandle::Proc(ref mut proc, ref reaped) => {
+                let mut reaped = reaped.lock().unwrap();
+                let exited = proc
+                    .try_wait()
+                    .map_err(|e| self.info.io_error(e, false))?
+                    .is_some();
+                *reaped |= exited;
+                Ok(exited)
+            }
+            CmdChildHandle::Thread(ref thread, _) => Ok(thread.is_finished()),
+            CmdChildHandle::SyncFn(_) => Ok(true),
+        }
+    }
+
+    fn signal(&mut self, sig: i32) -> CmdResult {
+        match self.handle {
+            CmdChildHandle::Proc(ref proc, ref reaped) => {
+                if !signal_proc(proc.id(), reaped, sig) {
+                    return Err(Error::last_os_error());
+                }
+            }
+            CmdChildHandle::Thread(_, ref cancelled) => {
+                if is_cancelling(sig) {
+                    cancelled.store(true, Ordering::SeqCst);
+                }
+            }
+            CmdChildHandle::SyncFn(_) => {}
         }
+        Ok(())
     }
 
-    fn wait(self, is_last: bool) -> CmdResult {
-        let res = self.handle.wait_with_stderr(self.stderr, &self.cmd);
+    fn wait(mut self, is_last: bool) -> CmdResult {
+        let polling_stderr = self.take_stderr_logging();
+        let res = self.handle.wait_with_stderr(polling_stderr, &self.info);
         if let Err(e) = res {
             if is_last || process::pipefail_enabled() {
                 return Err(e);
@@ -165,13 +718,37 @@ impl CmdChild {
         Ok(())
     }
 
-    fn wait_with_output(self, ignore_error: bool) -> Result<Vec<u8>> {
+    fn wait_status(mut self) -> Result<ExitStatus> {
+        let polling_stderr = self.take_stderr_logging();
+        if let Some(mut out) = self.stdout {
+            // drain the output, or the command might be blocked
+            let _ = io::copy(&mut out, &mut io::sink());
+        }
+        let is_proc = matches!(self.handle, CmdChildHandle::Proc(..));
+        match self.handle.wait_with_stderr(polling_stderr, &self.info) {
+            Ok(()) => Ok(ExitStatus::from_raw(0)),
+            Err(e) => {
+                if let Some(status) = CmdError::from_io_error(&e).and_then(CmdError::status) {
+                    Ok(status)
+                } else if is_proc {
+                    Err(e)
+                } else {
+                    // builtin or custom command failed, treat it as "exit 1"
+                    warn!("{}", e);
+                    Ok(ExitStatus::from_raw(1 << 8))
+                }
+            }
+        }
+    }
+
+    fn wait_with_output(mut self, ignore_error: bool) -> Result<Vec<u8>> {
+        let polling_stderr = self.take_stderr_logging();
         let buf = {
             if let Some(mut out) = self.stdout {
                 let mut buf = vec![];
                 if let Err(e) = out.read_to_end(&mut buf) {
                     if !ignore_error {
-                        return Err(CmdChildHandle::cmd_io_error(e, &self.cmd, false));
+                        return Err(self.info.io_error(e, false).into());
                     }
                 }
                 buf
@@ -179,7 +756,7 @@ impl CmdChild {
                 vec![]
             }
         };
-        let res = self.handle.wait_with_stderr(self.stderr, &self.cmd);
+        let res = self.handle.wait_with_stderr(polling_stderr, &self.info);
         if let Err(e) = res {
             if !ignore_error {
                 return Err(e);
@@ -189,104 +766,360 @@ impl CmdChild {
     }
 }
 
-pub(crate) enum CmdChildHandle {
-    Proc(Child),
-    Thread(JoinHandle<CmdResult>),
-    SyncFn(()),
+// "!" turns a failure into success, and a success into an error
+pub(crate) fn negate_result<T: Default>(full_cmds: Option<&str>, ret: Result<T>) -> Result<T> {
+    let full_cmds = match full_cmds {
+        Some(full_cmds) => full_cmds,
+        None => return ret,
+    };
+    match ret {
+        Ok(_) => {
+            let err_msg = format!("Running {} succeeded, expecting failure", full_cmds);
+            Err(Error::new(ErrorKind::Other, err_msg))
+        }
+        Err(_) => Ok(T::default()),
+    }
 }
 
-impl CmdChildHandle {
-    fn wait_with_stderr(self, stderr: Option<PipeReader>, cmd: &str) -> CmdResult {
-        let polling_stderr = StderrLogging::new(cmd, stderr);
-        match self {
-            CmdChildHandle::Proc(mut proc) => {
-                let status = proc.wait();
-                match status {
-                    Err(e) => return Err(CmdChildHandle::cmd_io_error(e, cmd, false)),
-                    Ok(status) => {
-                        if !status.success() {
-                            return Err(Self::status_to_io_error(
-                                status,
-                                &format!("Running {} exited with error", cmd),
-                            ));
-                        }
-                    }
-                }
-            }
-            CmdChildHandle::Thread(thread) => {
-                let status = thread.join();
-                match status {
-                    Ok(result) => {
-                        if let Err(e) = result {
-                            return Err(CmdChildHandle::cmd_io_error(e, cmd, false));
-                        }
-                    }
-                    Err(e) => {
-                        return Err(Error::new(
-                            ErrorKind::Other,
-                            format!("Running {} thread joined with error: {:?}", cmd, e),
-                        ))
-                    }
-                }
-            }
-            CmdChildHandle::SyncFn(_) => {}
+/// Command information attached to the errors
+#[derive(Clone)]
+pub(crate) struct CmdInfo {
+    cmd: String,
+    args: Vec<OsString>,
+    position: usize,
+    current_dir: PathBuf,
+}
+
+impl CmdInfo {
+    pub(crate) fn new(
+        cmd: String,
+        args: Vec<OsString>,
+        position: usize,
+        current_dir: &Path,
+    ) -> Self {
+        Self {
+            cmd,
+            args,
+            position,
+            current_dir: if current_dir.as_os_str().is_empty() {
+                std::env::current_dir().unwrap_or_default()
+            } else {
+                current_dir.into()
+            },
         }
-        drop(polling_stderr);
-        Ok(())
     }
 
-    fn cmd_io_error(e: Error, command: &str, spawning: bool) -> Error {
-        Error::new(
+    // command name without arguments
+    fn name(&self) -> String {
+        self.args
+            .first()
+            .map(|arg| arg.to_string_lossy().to_string())
+            .unwrap_or_default()
+    }
+
+    fn error(&self, kind: ErrorKind, msg: String) -> CmdError {
+        CmdError::new(
+            kind,
+            msg,
+            self.cmd.clone(),
+            self.args.clone(),
+            self.position,
+            self.current_dir.clone(),
+        )
+    }
+
+    pub(crate) fn io_error(&self, e: Error, spawning: bool) -> CmdError {
+        self.error(
             e.kind(),
             format!(
                 "{} {} failed: {}",
                 if spawning { "Spawning" } else { "Running" },
-                command,
+                self.cmd,
                 e
             ),
         )
     }
 
-    fn status_to_io_error(status: ExitStatus, command: &str) -> Error {
-        if let Some(code) = status.code() {
-            Error::new(
-                ErrorKind::Other,
-                format!("{}; status code: {}", command, code),
+    // errors returned by builtin or custom commands, which might be exiting with a status code,
+    // or passing on the exit status of the commands they run, like subshells do
+    pub(crate) fn fn_error(&self, e: Error) -> CmdError {
+        if let Some(code) = ExitCode::from_io_error(&e) {
+            return self.status_error(ExitStatus::from_raw(code << 8));
+        }
+        let status = CmdError::from_io_error(&e)
+            .filter(|cmd_err| cmd_err.kind() != ErrorKind::TimedOut)
+            .and_then(CmdError::status);
+        match status {
+            Some(status) => self.status_error(status),
+            None => self.io_error(e, false),
+        }
+    }
+
+    fn status_error(&self, status: ExitStatus) -> CmdError {
+        let msg = if let Some(code) = status.code() {
+            format!(
+                "Running {} exited with error; status code: {}",
+                self.cmd, code
             )
         } else {
-            Error::new(
-                ErrorKind::Other,
-                format!("{}; terminated by {}", command, status),
+            format!(
+                "Running {} exited with error; terminated by {}",
+                self.cmd, status
             )
+        };
+        self.error(ErrorKind::Other, msg).with_status(status)
+    }
+}
+
+pub(crate) enum CmdChildHandle {
+    // the process is only signaled before it is reaped, or its pid could be reused by others
+    Proc(Child, Arc<Mutex<bool>>),
+    Thread(JoinHandle<CmdResult>, Arc<AtomicBool>),
+    SyncFn(()),
+}
+
+// sends signal to the process unless it is already reaped, returning false on errors
+fn signal_proc(pid: u32, reaped: &Mutex<bool>, sig: i32) -> bool {
+    let reaped = reaped.lock().unwrap();
+    *reaped || unsafe { libc::kill(pid as libc::pid_t, sig) } == 0
+}
+
+// waits for the process to exit, and reaps it with the lock held, so it could not be signaled
+// after that
+fn wait_proc(proc: &mut Child, reaped: &Mutex<bool>) -> Result<ExitStatus> {
+    if !*reaped.lock().unwrap() {
+        loop {
+            // wait without reaping, so the process is kept as zombie until the lock is held
+            let mut info: libc::siginfo_t = unsafe { std::mem::zeroed() };
+            let flags = libc::WEXITED | libc::WNOWAIT;
+            let ret = unsafe { libc::waitid(libc::P_PID, proc.id(), &mut info, flags) };
+            if ret == 0 || Error::last_os_error().kind() != ErrorKind::Interrupted {
+                break;
+            }
+        }
+    }
+    let mut reaped = reaped.lock().unwrap();
+    let status = proc.wait();
+    *reaped = true;
+    status
+}
+
+// signals to cancel the builtin or custom commands
+fn is_cancelling(sig: i32) -> bool {
+    sig == libc::SIGINT || sig == libc::SIGTERM || sig == libc::SIGKILL
+}
+
+impl CmdChildHandle {
+    fn wait_with_stderr(self, polling_stderr: StderrLogging, info: &CmdInfo) -> CmdResult {
+        let res = match self {
+            CmdChildHandle::Proc(mut proc, reaped) => match wait_proc(&mut proc, &reaped) {
+                Err(e) => Err(info.io_error(e, false)),
+                Ok(status) if !status.success() => Err(info.status_error(status)),
+                Ok(_) => Ok(()),
+            },
+            CmdChildHandle::Thread(thread, _) => match thread.join() {
+                Ok(Err(e)) => Err(info.fn_error(e)),
+                Ok(Ok(())) => Ok(()),
+                Err(e) => Err(info.error(
+                    ErrorKind::Other,
+                    format!("Running {} thread joined with error: {:?}", info.cmd, e),
+                )),
+            },
+            CmdChildHandle::SyncFn(_) => Ok(()),
+        };
+        let stderr_tail = polling_stderr.join();
+        res.map_err(|e| e.with_stderr(stderr_tail).into())
+    }
+}
+
+/// Time limit for the children to finish
+#[derive(Clone, Copy)]
+pub(crate) struct Deadline {
+    instant: Instant,
+    timeout: Duration,
+}
+
+impl Deadline {
+    pub(crate) fn new(timeout: Duration) -> Self {
+        Self {
+            instant: Instant::now() + timeout,
+            timeout,
         }
     }
 }
 
+// Background thread to kill the children when the deadline is reached, or when the subshell
+// running them is cancelled
+struct Watchdog {
+    stop: Sender<()>,
+    thread: JoinHandle<bool>,
+    timeout: Duration,
+    info: Option<CmdInfo>,
+}
+
+impl Watchdog {
+    fn start(
+        children: &[Result<CmdChild>],
+        proc_substs: &[Result<CmdChild>],
+        deadline: Option<Deadline>,
+        kill_grace: Duration,
+        cancelled: &Option<Arc<AtomicBool>>,
+    ) -> Option<Self> {
+        if deadline.is_none() && cancelled.is_none() {
+            return None;
+        }
+        let cancelled = cancelled.clone();
+        let procs: Vec<_> = children
+            .iter()
+            .chain(proc_substs)
+            .filter_map(|child| child.as_ref().ok().and_then(CmdChild::reaped_flag))
+            .collect();
+        let cancel_flags: Vec<_> = children
+            .iter()
+            .chain(proc_substs)
+            .filter_map(|child| child.as_ref().ok().and_then(CmdChild::cancel_flag))
+            .collect();
+        let (stop, stopped) = mpsc::channel();
+        let thread = thread::spawn(move || {
+            let timed_out = loop {
+                let mut wait = match deadline {
+                    Some(deadline) => deadline.instant.saturating_duration_since(Instant::now()),
+                    None => CANCEL_CHECK_INTERVAL,
+                };
+                if cancelled.is_some() {
+                    wait = wait.min(CANCEL_CHECK_INTERVAL);
+                }
+                if stopped.recv_timeout(wait) != Err(RecvTimeoutError::Timeout) {
+                    return false;
+                }
+                if deadline.is_some_and(|deadline| Instant::now() >= deadline.instant) {
+                    break true;
+                }
+                if let Some(ref cancelled) = cancelled {
+                    if cancelled.load(Ordering::SeqCst) {
+                        break false;
+                    }
+                }
+            };
+            for cancelled in cancel_flags {
+                cancelled.store(true, Ordering::SeqCst);
+            }
+            Self::signal(&procs, libc::SIGTERM);
+            if stopped.recv_timeout(kill_grace) == Err(RecvTimeoutError::Timeout) {
+                Self::signal(&procs, libc::SIGKILL);
+            }
+            timed_out
+        });
+        Some(Self {
+            stop,
+            thread,
+            timeout: deadline
+                .map(|deadline| deadline.timeout)
+                .unwrap_or_default(),
+            info: children
+                .iter()
+                .rev()
+                .find_map(|child| child.as_ref().ok().map(|child| child.info.clone())),
+        })
+    }
+
+    fn signal(procs: &[(u32, Arc<Mutex<bool>>)], sig: i32) {
+        for (pid, reaped) in procs {
+            signal_proc(*pid, reaped, sig);
+        }
+    }
+
+    // stop the watchdog after all the children exited, and convert the result to
+    // timeout error if they were killed
+    fn stop<T>(watchdog: Option<Self>, ret: Result<T>) -> Result<T> {
+        let watchdog = match watchdog {
+            Some(watchdog) => watchdog,
+            None => return ret,
+        };
+        drop(watchdog.stop);
+        if !watchdog.thread.join().unwrap_or(false) {
+            return ret;
+        }
+        if let Err(e) = ret {
+            if CmdError::from_io_error(&e).is_some() {
+                let cmd_err = e.into_inner().unwrap().downcast::<CmdError>().unwrap();
+                return Err(cmd_err.timed_out(watchdog.timeout).into());
+            }
+        }
+        Err(match watchdog.info {
+            Some(info) => info
+                .error(ErrorKind::TimedOut, String::new())
+                .timed_out(watchdog.timeout)
+                .into(),
+            None => Error::new(
+                ErrorKind::TimedOut,
+                format!("Running timed out after {:?}", watchdog.timeout),
+            ),
+        })
+    }
+}
+
 struct StderrLogging {
-    thread: Option<JoinHandle<()>>,
+    thread: Option<JoinHandle<Vec<String>>>,
     cmd: String,
 }
 
 impl StderrLogging {
-    fn new(cmd: &str, stderr: Option<PipeReader>) -> Self {
+    fn new(info: &CmdInfo, stderr: Option<PipeReader>, policy: &StderrPolicy) -> Self {
         if let Some(stderr) = stderr {
-            let thread = std::thread::spawn(move || {
-                BufReader::new(stderr)
-                    .lines()
-                    .filter_map(|line| line.ok())
-                    .for_each(|line| info!("{}", line))
+            let name = info.name();
+            let policy = policy.clone();
+            let thread = thread::spawn(move || {
+                let mut tail = VecDeque::new();
+                let mut reader = BufReader::new(stderr);
+                let mut buf = vec![];
+                while let Ok(n) = reader.read_until(b'\n', &mut buf) {
+                    if n == 0 {
+                        break;
+                    }
+                    if let StderrPolicy::Capture(ref output) = policy {
+                        output.lock().unwrap().extend_from_slice(&buf);
+                    }
+                    if buf.ends_with(b"\n") {
+                        buf.pop();
+                    }
+                    let line = String::from_utf8_lossy(&buf).to_string();
+                    match policy {
+                        StderrPolicy::LogPlain(level) => log!(level, "{}", line),
+                        StderrPolicy::Log(level) => log!(level, "[{}] {}", name, line),
+                        StderrPolicy::Callback(ref f) => f(&name, &buf),
+                        _ => {}
+                    }
+                    if tail.len() == STDERR_TAIL_LINES {
+                        tail.pop_front();
+                    }
+                    tail.push_back(line);
+                    buf.clear();
+                }
+                tail.into()
             });
             Self {
-                cmd: cmd.into(),
+                cmd: info.cmd.clone(),
                 thread: Some(thread),
             }
         } else {
             Self {
-                cmd: cmd.into(),
+                cmd: info.cmd.clone(),
                 thread: None,
             }
         }
     }
+
+    // wait for all the stderr output, returning the last lines of it
+    fn join(mut self) -> Vec<String> {
+        if let Some(thread) = self.thread.take() {
+            match thread.join() {
+                Ok(tail) => return tail,
+                Err(e) => warn!("{} logging thread exited with error: {:?}", self.cmd, e),
+            }
+        }
+        vec![]
+    }
 }
 
 impl Drop for StderrLogging {
@@ -0,0 +1,152 @@
+use std::error;
+use std::ffi::OsString;
+use std::fmt;
+use std::io::{Error, ErrorKind};
+use std::os::unix::process::ExitStatusExt;
+use std::path::{Path, PathBuf};
+use std::process::ExitStatus;
+use std::time::Duration;
+
+/// Error details of a failed command
+///
+/// All the errors returned by the macros and the spawned children are still `std::io::Error`,
+/// and the ones caused by running a command are carrying a `CmdError` inside, which can be
+/// fetched by [`CmdError::from_io_error`]:
+///
+/// ```
+/// # use cmd_lib::*;
+/// let err = run_cmd!(ls /nofile).unwrap_err();
+/// let cmd_err = CmdError::from_io_error(&err).unwrap();
+/// assert_eq!(cmd_err.args()[0], "ls");
+/// assert_eq!(cmd_err.code(), Some(2));
+/// ```
+#[derive(Debug)]
+pub struct CmdError {
+    kind: ErrorKind,
+    msg: String,
+    cmd: String,
+    args: Vec<OsString>,
+    position: usize,
+    current_dir: PathBuf,
+    status: Option<ExitStatus>,
+    stderr: Vec<String>,
+}
+
+impl CmdError {
+    pub(crate) fn new(
+        kind: ErrorKind,
+        msg: String,
+        cmd: String,
+        args: Vec<OsString>,
+        position: usize,
+        current_dir: PathBuf,
+    ) -> Self {
+        Self {
+            kind,
+            msg,
+            cmd,
+            args,
+            position,
+            current_dir,
+            status: None,
+            stderr: vec![],
+        }
+    }
+
+    pub(crate) fn with_status(mut self, status: ExitStatus) -> Self {
+        self.status = Some(status);
+        self
+    }
+
+    pub(crate) fn with_stderr(mut self, stderr: Vec<String>) -> Self {
+        self.stderr = stderr;
+        self
+    }
+
+    pub(crate) fn timed_out(mut self, timeout: Duration) -> Self {
+        self.kind = ErrorKind::TimedOut;
+        self.msg = format!("Running {} timed out after {:?}", self.cmd, timeout);
+        self
+    }
+
+    /// Fetches the `CmdError` carried by an `std::io::Error`, if any
+    pub fn from_io_error(e: &Error) -> Option<&CmdError> {
+        e.get_ref().and_then(|e| e.downcast_ref::<CmdError>())
+    }
+
+    /// Returns the corresponding `std::io::ErrorKind` for this error
+    ///
+    /// Commands killed because of timeout are reported with `ErrorKind::TimedOut`.
+    pub fn kind(&self) -> ErrorKind {
+        self.kind
+    }
+
+    /// Returns the arguments of the failed command, starting with the command name
+    pub fn args(&self) -> &[OsString] {
+        &self.args
+    }
+
+    /// Returns the position of the failed command in its pipeline, starting from 0
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    /// Returns the working directory of the failed command
+    pub fn current_dir(&self) -> &Path {
+        &self.current_dir
+    }
+
+    /// Returns the exit status, if the command did run and exit with error
+    pub fn status(&self) -> Option<ExitStatus> {
+        self.status
+    }
+
+    /// Returns the exit code, if the command exited with a non-zero code
+    pub fn code(&self) -> Option<i32> {
+        self.status.and_then(|status| status.code())
+    }
+
+    /// Returns the signal number, if the command was terminated by a signal
+    pub fn signal(&self) -> Option<i32> {
+        self.status.and_then(|status| status.signal())
+    }
+
+    /// Returns the last lines the command printed to stderr
+    pub fn stderr(&self) -> &[String] {
+        &self.stderr
+    }
+}
+
+impl fmt::Display for CmdError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.msg)
+    }
+}
+
+impl error::Error for CmdError {}
+
+impl From<CmdError> for Error {
+    fn from(e: CmdError) -> Self {
+        Error::new(e.kind, e)
+    }
+}
+
+// exit code of a builtin or custom command, carried by the `std::io::Error` it returns
+#[derive(Debug)]
+pub(crate) struct ExitCode(pub(crate) i32);
+
+impl ExitCode {
+    pub(crate) fn from_io_error(e: &Error) -> Option<i32> {
+        e.get_ref()
+            .and_then(|e| e.downcast_ref::<ExitCode>())
+            .map(|code| code.0)
+    }
+}
+
+impl fmt::Display for ExitCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "exit code {}", self.0)
+    }
+}
+
+impl error::Error for ExitCode {}
@@ -20,6 +20,16 @@ impl Read for CmdIn {
     }
 }
 
+impl CmdIn {
+    pub fn try_clone(&self) -> Result<Self> {
+        match self {
+            CmdIn::Null => Ok(CmdIn::Null),
+            CmdIn::File(file) => file.try_clone().map(CmdIn::File),
+            CmdIn::Pipe(pipe) => pipe.try_clone().map(CmdIn::Pipe),
+        }
+    }
+}
+
 impl From<CmdIn> for Stdio {
     fn from(cmd_in: CmdIn) -> Stdio {
         match cmd_in {
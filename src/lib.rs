@@ -111,6 +111,22 @@
 //! }.is_err() {
 //!     // your error handling code
 //! }
+//!
+//! // commands can be chained with "&&" and "||" like in bash, and similar to "set -e", only the
+//! // failure of the last command in the chain will stop the group, while the last chain returns
+//! // the result of its last command which ran, like "$?"
+//! run_cmd! {
+//!     test -d /tmp/cmd_lib || mkdir /tmp/cmd_lib;
+//!     ls /tmp/cmd_lib/nofile && echo "found";
+//!     rmdir /tmp/cmd_lib;
+//! }?;
+//!
+//! // commands in parentheses run in a subshell, where "cd" only applies inside, and the
+//! // output of the whole subshell can be redirected or piped
+//! run_cmd! {
+//!     (cd /tmp; ls; pwd) > /tmp/cmd_lib_ls.txt;
+//!     rm /tmp/cmd_lib_ls.txt;
+//! }?;
 //! # Ok::<(), std::io::Error>(())
 //! ```
 //!
@@ -127,6 +143,40 @@
 //! # Ok::<(), std::io::Error>(())
 //! ```
 //!
+//! - run_fun_bytes! --> Result<Vec<u8>>
+//!
+//! ```
+//! # use cmd_lib::run_fun_bytes;
+//! // the output is kept as it is, without UTF-8 decoding or newline stripping
+//! let gz = run_fun_bytes!(echo "rust" | gzip -c)?;
+//! assert_eq!(&gz[..2], b"\x1f\x8b");
+//! # Ok::<(), std::io::Error>(())
+//! ```
+//!
+//! - run_statuses! --> Result<Vec<ExitStatus>>
+//!
+//! ```
+//! # use cmd_lib::run_statuses;
+//! // the same as PIPESTATUS in bash, to tell which command in the pipeline failed
+//! let statuses = run_statuses!(echo "rust" | grep -q go | head -1)?;
+//! if !statuses[1].success() {
+//!     eprintln!("grep found nothing");
+//! }
+//! # Ok::<(), std::io::Error>(())
+//! ```
+//!
+//! - run_fun_full! --> Result<Output>
+//!
+//! ```
+//! # use cmd_lib::run_fun_full;
+//! // stderr is captured instead of being logged, and failing commands are not errors
+//! let output = run_fun_full!(ls /nofile)?;
+//! if !output.status.success() {
+//!     eprintln!("ls failed: {}", String::from_utf8_lossy(&output.stderr));
+//! }
+//! # Ok::<(), std::io::Error>(())
+//! ```
+//!
 //! ### Abstraction without overhead
 //!
 //! Since all the macros' lexical analysis and syntactic analysis happen at compile time, it can
@@ -179,10 +229,128 @@
 //! # Ok::<(), std::io::Error>(())
 //! ```
 //!
+//! The output of other commands can be passed with `$(cmds)`, also inside string literals, which
+//! is trimmed of trailing newlines and passed as a single parameter. To split the output by
+//! whitespaces into multiple parameters, use `$[(cmds)]` instead. The inner commands are run right
+//! before the command is spawned, and their failure is returned as the error of the whole
+//! command. Inside string literals, use `$$` to escape a literal `$(`.
+//! ```no_run
+//! # use cmd_lib::run_cmd;
+//! run_cmd!(echo "Today is $(date +%F)")?;
+//! run_cmd!(kill $[(pgrep -x my_server)])?;
+//! # Ok::<(), std::io::Error>(())
+//! ```
+//!
+//! Since `$var` is always a rust variable, the environment variables of the process are read with
+//! `${env.NAME}`, also inside string literals, and the unset ones are expanded to empty strings.
+//! An unquoted `~` or `~user` at the beginning of a parameter is expanded to the home directory:
+//! ```no_run
+//! # use cmd_lib::run_cmd;
+//! run_cmd!(echo "Hello ${env.USER}"; ls ${env.HOME}/.config ~/.cache ~root)?;
+//! # Ok::<(), std::io::Error>(())
+//! ```
+//!
 //! ### Redirection and Piping
 //! Right now piping and stdin, stdout, stderr redirection are supported. Most parts are the same as in
 //! [bash scripts](https://www.gnu.org/software/bash/manual/html_node/Redirections.html#Redirections).
 //!
+//! Other file descriptors can be redirected as well, like `3> file`, `4< file`, `5<> file` (open
+//! for reading and writing), `3>&1` (duplicate) or `2>&-` (close), which are applied in order.
+//! Builtin or custom commands and subshells only have the stdio, so redirecting other file
+//! descriptors for them is an error.
+//!
+//! ```
+//! # use cmd_lib::*;
+//! assert_eq!(run_fun!(sh -c "echo status >&3" 3>&1 > /dev/null)?, "status");
+//! # Ok::<(), std::io::Error>(())
+//! ```
+//!
+//! The stdin can also be fed from rust values, with `<<< word` (here-string, with a newline
+//! appended) or `<< word` (the data as it is). A single variable can be any `AsRef<[u8]>` type,
+//! whose bytes are used as they are, or any type allowed as arguments.
+//!
+//! ```
+//! # use cmd_lib::*;
+//! let name = "rust";
+//! assert_eq!(run_fun!(cat <<< "hello, $name")?, "hello, rust");
+//!
+//! let data: Vec<u8> = vec![0, 1, 2];
+//! assert_eq!(run_fun!(wc -c << $data)?, "3");
+//! # Ok::<(), std::io::Error>(())
+//! ```
+//!
+//! In a group of commands, the stdout of one command can be captured into a `Vec<u8>` or `String`
+//! variable with `> &mut $buf` (or `>> &mut $buf` to append), while the rest of the group keeps
+//! running with the same state. Each buffer can only be used once in a macro call, and it is
+//! not supported by `spawn!`/`spawn_with_output!`, or in the last commands of `run_statuses!` and
+//! `run_fun_full!`.
+//!
+//! ```
+//! # use cmd_lib::*;
+//! let mut files = String::new();
+//! let dir = run_fun! {
+//!     cd /tmp;
+//!     ls > &mut $files;
+//!     pwd;
+//! }?;
+//! assert_eq!(dir, "/tmp");
+//! # Ok::<(), std::io::Error>(())
+//! ```
+//!
+//! Process substitution is supported with `<(cmds)` and `>(cmds)`, which are replaced by a
+//! `/dev/fd/N` path connected to the inner pipeline. The inner commands are waited together with
+//! the outer ones, and their failures are reported in the same way.
+//!
+//! ```
+//! # use cmd_lib::*;
+//! run_cmd!(diff <(echo a b | tr " " "\n") <(printf "a\nb\n"))?;
+//! # Ok::<(), std::io::Error>(())
+//! ```
+//!
+//! ### Macro attributes
+//!
+//! Options for a single macro invocation can be set by attributes at the beginning, taking rust
+//! expressions as their values:
+//! - `#[timeout(duration)]`: kill all the running commands, including the ones in command
+//!   substitutions and subshells, when the whole invocation takes longer than `duration`, and
+//!   return an error of `ErrorKind::TimedOut`
+//! - `#[kill_grace(duration)]`: how long to wait before sending `SIGKILL` to the commands which
+//!   are still running after `SIGTERM`, 5 seconds by default
+//! - `#[stderr(policy)]`: how to handle the stderr of the commands, see [`StderrPolicy`]
+//! - `#[glob(policy)]`: how to expand the glob patterns, see [`GlobPolicy`]
+//! - `#[piped_stdin]`: connect the stdin of the first command to a pipe, which can be written by
+//!   the handle from `take_stdin()` of the spawned children
+//! - `#[env_clear]`: start the commands from an empty environment, like `env -i`, and only the
+//!   variables set in the macro call are passed
+//! - `#[env_keep(keys)]`: the same as `#[env_clear]`, but keeping the given variables of the
+//!   current process
+//! - `#[env_remove(keys)]`: remove the given variables from the environment of the commands
+//!
+//! The `env_*` attributes can also be put at the start of a pipeline, after `;`, `&&`, `||` or
+//! `&`, and they only change the environment of the commands in that pipeline.
+//!
+//! ```
+//! # use cmd_lib::*;
+//! # use std::io::ErrorKind;
+//! # use std::time::Duration;
+//! let timeout = Duration::from_millis(100);
+//! let err = run_cmd!(#[timeout(timeout)] sleep 10).unwrap_err();
+//! assert_eq!(err.kind(), ErrorKind::TimedOut);
+//!
+//! // the spawned children can also be waited with timeout
+//! let mut proc = spawn!(sleep 10)?;
+//! assert!(proc.wait_timeout(timeout).is_err());
+//!
+//! // only "PATH" is inherited from the current process
+//! let vars = run_fun!(#[env_keep(["PATH"])] LANG=C env)?;
+//! assert!(vars.lines().all(|var| var.starts_with("PATH=") || var == "LANG=C"));
+//!
+//! // "FOO" is only removed for the first "printenv"
+//! let foo = run_fun!(export FOO=1; #[env_remove(["FOO"])] printenv FOO || printenv FOO)?;
+//! assert_eq!(foo, "1");
+//! # Ok::<(), std::io::Error>(())
+//! ```
+//!
 //! ### Logging
 //!
 //! This library provides convenient macros and builtin commands for logging. All messages which
@@ -203,6 +371,23 @@
 //! It is using rust [log crate](https://crates.io/crates/log), and you can use your actual favorite
 //! logging implementation. Notice that if you don't provide any logger, the stderr output will be discarded.
 //!
+//! The stderr output is logged with info level by default, line by line as it is. It can be
+//! changed globally with `set_stderr_policy()`, or for one macro call with `#[stderr(policy)]`
+//! attribute, e.g. `StderrPolicy::Log(level)` also prefixes each line with the command name:
+//!
+//! ```
+//! # use cmd_lib::*;
+//! # use std::sync::{Arc, Mutex};
+//! set_stderr_policy(StderrPolicy::Log(log::Level::Warn));
+//!
+//! let buf = Arc::new(Mutex::new(vec![]));
+//! run_cmd!(#[stderr(StderrPolicy::Capture(buf.clone()))] echo "oops" >&2)?;
+//! assert_eq!(*buf.lock().unwrap(), b"oops\n");
+//!
+//! run_cmd!(#[stderr(StderrPolicy::Discard)] ls /nofile; echo "discarded" >&2).unwrap_err();
+//! # Ok::<(), std::io::Error>(())
+//! ```
+//!
 //! ### Builtin commands
 //! #### cd
 //! cd: set process current directory, which can be used without importing.
@@ -225,6 +410,51 @@
 //!
 //! Ignore errors for command execution, which can be used without importing.
 //!
+//! #### !
+//!
+//! Invert the result of the pipeline, like in bash: a failure becomes success, and a success
+//! becomes an error. It can be used without importing, and only at the start of a pipeline.
+//! ```
+//! # use cmd_lib::run_cmd;
+//! run_cmd!(! grep -q "rust" <<< "cmd_lib")?;
+//! # Ok::<(), std::io::Error>(())
+//! ```
+//!
+//! #### wait
+//!
+//! Wait for the background jobs started with `&` in the same macro call, returning the first
+//! error of them. It can be used without importing. Their output is not captured by `run_fun!`.
+//! The jobs still running at the end are also waited before the macro call returns, and their
+//! failures are only reported as warnings.
+//! ```no_run
+//! # use cmd_lib::run_cmd;
+//! run_cmd! {
+//!     my_server &
+//!     sleep 1;
+//!     my_client;
+//!     wait;
+//! }?;
+//! # Ok::<(), std::io::Error>(())
+//! ```
+//!
+//! #### export/unset
+//!
+//! Set or remove environment variables for the later commands in the same macro call, without
+//! changing the environment of the current process. Subshells and command substitutions inherit
+//! them, but their own changes are not visible outside. They can be used without importing.
+//! `${env.NAME}` in the later commands also reads them, except in redirection targets, which
+//! always read the environment of the current process.
+//! ```no_run
+//! # use cmd_lib::run_cmd;
+//! run_cmd! {
+//!     export RUST_LOG=debug CARGO_TARGET_DIR=/tmp/target;
+//!     unset RUSTFLAGS;
+//!     cargo build;
+//!     cargo test;
+//! }?;
+//! # Ok::<(), std::io::Error>(())
+//! ```
+//!
 //! #### echo
 //!
 //! Print messages to stdout, which needs to be imported with `use_builtin_cmd!` macro.
@@ -256,6 +486,42 @@
 //! # Ok::<(), std::io::Error>(())
 //! ```
 //!
+//! Commands can also be registered at runtime with `register_cmd()`, which accepts closures and
+//! other [`CustomCmd`] implementations, like mock commands in tests or commands with their own
+//! configurations:
+//! ```
+//! # use cmd_lib::*;
+//! # use std::io::Write;
+//! let server = "https://staging.example.com";
+//! register_cmd("deploy", move |env: &mut CmdEnv| {
+//!     let app = env.args()[1].clone();
+//!     writeln!(env.stdout(), "deploying {} to {}", app, server)
+//! });
+//! run_cmd!(deploy my_app)?;
+//! # Ok::<(), std::io::Error>(())
+//! ```
+//!
+//! The commands get the same environment variables as external commands with `CmdEnv::var()`,
+//! including the ones from `export` and macro attributes. Use `CmdEnv::args_os()` and
+//! `CmdEnv::var_os()` for the values which might not be valid unicode, like file names.
+//!
+//! Besides returning an error, the commands can also exit with a status code by
+//! `CmdEnv::exit()`, which is reported the same way as the ones from external commands:
+//! ```
+//! # use cmd_lib::*;
+//! # use std::io::Read;
+//! #[export_cmd(has_error)]
+//! fn has_error(env: &mut CmdEnv) -> CmdResult {
+//!     let mut input = String::new();
+//!     env.stdin().read_to_string(&mut input)?;
+//!     env.exit(if input.contains("error") { 0 } else { 1 })
+//! }
+//!
+//! use_custom_cmd!(has_error);
+//! let err = run_cmd!(echo "all good" | has_error).unwrap_err();
+//! assert_eq!(CmdError::from_io_error(&err).unwrap().code(), Some(1));
+//! ```
+//!
 //! ### Low-level process spawning macros
 //!
 //! `spawn!` macro executes the whole command as a child process, returning a handle to it. By
@@ -264,7 +530,7 @@
 //! for the process to finish.
 //!
 //! With `spawn_with_output!` you can get output by calling `wait_with_output()`, or even do stream
-//! processing with `wait_with_pipe()`.
+//! processing with `lines()` or `wait_with_pipe()`.
 //!
 //! ```no_run
 //! # use cmd_lib::*;
@@ -287,9 +553,58 @@
 //!         .take(10)
 //!         .for_each(|line| println!("{}", line));
 //! })?;
+//!
+//! // the lines are read while the commands are running, and the commands are waited at the end
+//! for line in spawn_with_output!(journalctl -f | grep usb)?.lines().take(10) {
+//!     println!("{}", line?);
+//! }
+//! # Ok::<(), std::io::Error>(())
+//! ```
+//!
+//! To drive the commands from rust, spawn them with `#[piped_stdin]` attribute and write to the
+//! handle returned by `take_stdin()`:
+//!
+//! ```
+//! # use cmd_lib::*;
+//! # use std::io::Write;
+//! let mut proc = spawn_with_output!(#[piped_stdin] tr a-z A-Z)?;
+//! let mut stdin = proc.take_stdin().unwrap();
+//! writeln!(stdin, "hello")?;
+//! drop(stdin);
+//! assert_eq!(proc.wait_with_output()?, "HELLO");
+//! # Ok::<(), std::io::Error>(())
+//! ```
+//!
+//! The spawned children can also be checked with `try_wait()` without blocking, and stopped with
+//! `kill()` or `signal()`. Builtin and custom commands are not processes, they are asked to
+//! cancel instead, and can check it with `CmdEnv::is_cancelled()`.
+//!
+//! ```no_run
+//! # use cmd_lib::*;
+//! let mut proc = spawn!(sleep 100 | cat)?;
+//! println!("pids: {:?}", proc.pids());
+//! if !proc.try_wait()? {
+//!     proc.kill()?;
+//! }
+//! assert!(proc.wait().is_err());
 //! # Ok::<(), std::io::Error>(())
 //! ```
 //!
+//! ### Error details
+//!
+//! The macros and the spawned children report failures as `std::io::Error`. When the error is
+//! caused by a command, it carries a [`CmdError`] with the command arguments, its position in the
+//! pipeline, the exit code or signal, the working directory and the last lines of its stderr.
+//!
+//! ```
+//! # use cmd_lib::*;
+//! if let Err(e) = run_cmd!(echo "nothing" | grep rust) {
+//!     let cmd_err = CmdError::from_io_error(&e).unwrap();
+//!     assert_eq!(cmd_err.args(), ["grep", "rust"]);
+//!     assert_eq!(cmd_err.position(), 1);
+//!     assert_eq!(cmd_err.code(), Some(1));
+//! }
+//! ```
 //!
 //! ### Macros to define, get and set thread-local global variables
 //! - `tls_init!` to define thread local global variable
@@ -337,8 +652,28 @@
 //!
 //! ### Glob/Wildcard
 //!
-//! This library does not provide glob functions, to avoid silent errors and other surprises.
-//! You can use the [glob](https://github.com/rust-lang-nursery/glob) package instead.
+//! Glob expansion is disabled by default, to avoid silent errors and other surprises. Once it is
+//! enabled with `set_glob_policy()`, or for one macro call with `#[glob(policy)]`, the unquoted `*`, `?`, `[...]` and `**` patterns in the
+//! arguments are expanded when the commands are spawned, relative to the current directory. The
+//! quoted strings and the variables are always kept as they are:
+//!
+//! ```no_run
+//! # use cmd_lib::*;
+//! set_glob_policy(GlobPolicy::Strict);
+//! let pattern = "*.rs";
+//! run_cmd!(ls *.toml "*.md" $pattern)?; // only "*.toml" is expanded
+//! run_cmd!(cd src; ls **/mod.rs [a-c]?.rs)?;
+//! # Ok::<(), std::io::Error>(())
+//! ```
+//!
+//! With `GlobPolicy::Strict`, a pattern matching nothing fails the command with an error of
+//! `ErrorKind::NotFound`. `GlobPolicy::PassThrough` keeps the pattern as it is instead, like bash,
+//! and `GlobPolicy::Null` removes it. Since `/*` starts a comment in rust, the path before a pattern
+//! needs to be quoted when it ends with a slash, like `"src/"*.rs` or `${dir}"/"*`.
+//!
+//! Notice that an unquoted `[...]` used to be a compile error, and it is now a pattern everywhere,
+//! including at the start of a command. So the `[ ... ]` test command of shells is not available,
+//! use `test ...` instead.
 //!
 //! ### Thread Safety
 //!
@@ -349,7 +684,8 @@
 
 pub use cmd_lib_macros::{
     cmd_debug, cmd_die, cmd_echo, cmd_error, cmd_info, cmd_trace, cmd_warn, export_cmd, run_cmd,
-    run_fun, spawn, spawn_with_output, use_builtin_cmd, use_custom_cmd,
+    run_fun, run_fun_bytes, run_fun_full, run_statuses, spawn, spawn_with_output, use_builtin_cmd,
+    use_custom_cmd,
 };
 /// Return type for run_fun!() macro
 pub type FunResult = std::io::Result<String>;
@@ -359,16 +695,20 @@ pub use builtins::{
     builtin_cat, builtin_debug, builtin_die, builtin_echo, builtin_error, builtin_info,
     builtin_trace, builtin_warn,
 };
-pub use child::{CmdChildren, FunChildren};
+pub use child::{CmdChildren, FunChildren, FunLines};
+pub use error::CmdError;
 #[doc(hidden)]
 pub use log;
 pub use logger::init_builtin_logger;
 pub use process::{
-    export_cmd, set_debug, set_pipefail, AsOsStr, Cmd, CmdEnv, CmdString, Cmds, GroupCmds, Redirect,
+    export_cmd, register_cmd, set_debug, set_glob_policy, set_pipefail, set_stderr_policy, AsOsStr,
+    Cmd, CmdEnv, CmdString, Cmds, CustomCmd, GlobPolicy, GroupCmds, OutputBuffer, Redirect,
+    StderrPolicy, StdinArg, StdinBytes, StdinVar, SubstArg,
 };
 
 mod builtins;
 mod child;
+mod error;
 mod io;
 mod logger;
 mod process;
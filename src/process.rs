@@ -1,22 +1,37 @@
-use crate::child::{CmdChild, CmdChildHandle, CmdChildren, FunChildren};
+use crate::child::{self, CmdChild, CmdChildHandle, CmdChildren, CmdInfo, Deadline, FunChildren};
+use crate::error::ExitCode;
 use crate::io::{CmdIn, CmdOut};
-use crate::{CmdResult, FunResult};
+use crate::{CmdError, CmdResult, FunResult};
 use faccess::{AccessMode, PathExt};
 use lazy_static::lazy_static;
 use log::{debug, warn};
 use os_pipe::{self, PipeReader, PipeWriter};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::ffi::{OsStr, OsString};
 use std::fmt;
 use std::fs::{File, OpenOptions};
 use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, RawFd};
+use std::os::unix::process::{CommandExt, ExitStatusExt};
 use std::path::{Path, PathBuf};
-use std::process::Command;
-use std::sync::Mutex;
+use std::process::{Command, ExitStatus, Output};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::Duration;
 
 const CD_CMD: &str = "cd";
 const IGNORE_CMD: &str = "ignore";
+const NEGATE_CMD: &str = "!";
+const WAIT_CMD: &str = "wait";
+const EXPORT_CMD: &str = "export";
+const UNSET_CMD: &str = "unset";
+
+// prefixes which are not part of the command itself
+fn is_prefix(arg: &OsStr) -> bool {
+    arg == IGNORE_CMD || arg == NEGATE_CMD
+}
 
 /// Environment for builtin or custom commands
 pub struct CmdEnv {
@@ -24,20 +39,46 @@ pub struct CmdEnv {
     stdout: CmdOut,
     stderr: CmdOut,
     args: Vec<String>,
+    args_os: Vec<OsString>,
     vars: HashMap<String, String>,
+    vars_os: HashMap<OsString, OsString>,
     current_dir: PathBuf,
+    cancelled: Arc<AtomicBool>,
 }
 impl CmdEnv {
     /// Returns the arguments for this command
+    ///
+    /// Invalid unicode in the arguments is replaced with `U+FFFD`, use [`CmdEnv::args_os`] to get
+    /// them as they are.
     pub fn args(&self) -> &[String] {
         &self.args
     }
 
+    /// Returns the arguments for this command, without any conversion
+    pub fn args_os(&self) -> &[OsString] {
+        &self.args_os
+    }
+
     /// Fetches the environment variable key for this command
+    ///
+    /// It is the same environment an external command would get, including the variables of the
+    /// current process. Variables with invalid unicode values are not returned.
     pub fn var(&self, key: &str) -> Option<&String> {
         self.vars.get(key)
     }
 
+    /// Fetches the environment variable key for this command, without any conversion
+    pub fn var_os<K: AsRef<OsStr>>(&self, key: K) -> Option<&OsStr> {
+        self.vars_os.get(key.as_ref()).map(OsString::as_os_str)
+    }
+
+    /// Returns an iterator of all the environment variables for this command
+    pub fn vars_os(&self) -> impl Iterator<Item = (&OsStr, &OsStr)> {
+        self.vars_os
+            .iter()
+            .map(|(key, value)| (key.as_os_str(), value.as_os_str()))
+    }
+
     /// Returns the current working directory for this command
     pub fn current_dir(&self) -> &Path {
         &self.current_dir
@@ -57,21 +98,69 @@ impl CmdEnv {
     pub fn stderr(&mut self) -> impl Write + '_ {
         &mut self.stderr
     }
+
+    /// Returns the result of exiting with status `code`, like `exit` in bash
+    ///
+    /// A non-zero code fails the command the same way as an external command exiting with it,
+    /// which is reported by [`CmdError::code`](crate::CmdError::code) and the exit statuses.
+    /// Only the lowest 8 bits of the code are kept, as in unix.
+    pub fn exit(&self, code: i32) -> CmdResult {
+        match code & 0xff {
+            0 => Ok(()),
+            code => Err(Error::new(ErrorKind::Other, ExitCode(code))),
+        }
+    }
+
+    /// Returns true if this command is asked to cancel, by killing or signaling the children
+    ///
+    /// Long running commands should check it from time to time, and exit early when it is set.
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
 }
 
 type FnFun = fn(&mut CmdEnv) -> CmdResult;
 
+/// Custom command, which can be registered by [`register_cmd`]
+///
+/// It is implemented for all the functions and closures taking `&mut CmdEnv`, and can also be
+/// implemented by structs to keep configurations or states.
+pub trait CustomCmd: Send + Sync {
+    /// Runs the command with its environment
+    fn run(&self, env: &mut CmdEnv) -> CmdResult;
+}
+
+impl<F> CustomCmd for F
+where
+    F: Fn(&mut CmdEnv) -> CmdResult + Send + Sync,
+{
+    fn run(&self, env: &mut CmdEnv) -> CmdResult {
+        self(env)
+    }
+}
+
 lazy_static! {
-    static ref CMD_MAP: Mutex<HashMap<OsString, FnFun>> = {
+    static ref CMD_MAP: Mutex<HashMap<OsString, Arc<dyn CustomCmd>>> = {
         // needs explicit type, or it won't compile
-        let m: HashMap<OsString, FnFun> = HashMap::new();
+        let m: HashMap<OsString, Arc<dyn CustomCmd>> = HashMap::new();
         Mutex::new(m)
     };
 }
 
 #[doc(hidden)]
 pub fn export_cmd(cmd: &'static str, func: FnFun) {
-    CMD_MAP.lock().unwrap().insert(OsString::from(cmd), func);
+    register_cmd(cmd, func);
+}
+
+/// Registers a custom command at runtime, replacing the one with the same name if any
+///
+/// Unlike `#[export_cmd]`, the command can be a closure capturing its environment, or any other
+/// [`CustomCmd`] implementation.
+pub fn register_cmd<C: CustomCmd + 'static>(name: &str, cmd: C) {
+    CMD_MAP
+        .lock()
+        .unwrap()
+        .insert(OsString::from(name), Arc::new(cmd));
 }
 
 /// set debug mode or not, false by default
@@ -88,6 +177,84 @@ pub fn set_pipefail(enable: bool) {
     std::env::set_var("CMD_LIB_PIPEFAIL", if enable { "1" } else { "0" });
 }
 
+/// How the stderr of the commands is handled, see [`set_stderr_policy`]
+#[derive(Clone)]
+pub enum StderrPolicy {
+    /// Inherits the stderr of the current process
+    Inherit,
+    /// Logs each line with the given level as it is, which is the default with info level
+    LogPlain(log::Level),
+    /// Logs each line with the given level, prefixed with the command name like `[ls] ...`
+    Log(log::Level),
+    /// Appends all the output to the buffer
+    Capture(Arc<Mutex<Vec<u8>>>),
+    /// Discards the output
+    Discard,
+    /// Calls the function with the command name and each line, without the trailing newline
+    Callback(StderrCallback),
+}
+
+type StderrCallback = Arc<dyn Fn(&str, &[u8]) + Send + Sync>;
+
+impl StderrPolicy {
+    /// Creates a `StderrPolicy::Callback` from the function
+    pub fn callback<F>(f: F) -> Self
+    where
+        F: Fn(&str, &[u8]) + Send + Sync + 'static,
+    {
+        StderrPolicy::Callback(Arc::new(f))
+    }
+}
+
+impl Default for StderrPolicy {
+    fn default() -> Self {
+        StderrPolicy::LogPlain(log::Level::Info)
+    }
+}
+
+/// How the unquoted glob patterns (`*`, `?`, `[...]` and `**`) are expanded, see [`set_glob_policy`]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum GlobPolicy {
+    /// Passes the patterns as they are, without expansion
+    #[default]
+    Disabled,
+    /// Expands the patterns, and returns an error if nothing matches
+    Strict,
+    /// Expands the patterns, and passes them as they are if nothing matches, like bash
+    PassThrough,
+    /// Expands the patterns, and removes them if nothing matches, like bash "nullglob" option
+    Null,
+}
+
+lazy_static! {
+    static ref STDERR_POLICY: Mutex<StderrPolicy> = Mutex::new(StderrPolicy::default());
+    static ref GLOB_POLICY: Mutex<GlobPolicy> = Mutex::new(GlobPolicy::default());
+}
+
+/// set how the stderr of the commands is handled, logging with info level by default
+///
+/// It can also be set for one macro call with `#[stderr(policy)]` attribute.
+/// Explicit stderr redirections like `2>file` always take precedence.
+pub fn set_stderr_policy(policy: StderrPolicy) {
+    *STDERR_POLICY.lock().unwrap() = policy;
+}
+
+pub(crate) fn stderr_policy() -> StderrPolicy {
+    STDERR_POLICY.lock().unwrap().clone()
+}
+
+/// set how the glob patterns in unquoted arguments are expanded, disabled by default
+///
+/// It can also be set for one macro call with `#[glob(policy)]` attribute.
+/// Quoted strings and variables are never expanded.
+pub fn set_glob_policy(policy: GlobPolicy) {
+    *GLOB_POLICY.lock().unwrap() = policy;
+}
+
+pub(crate) fn glob_policy() -> GlobPolicy {
+    *GLOB_POLICY.lock().unwrap()
+}
+
 pub(crate) fn debug_enabled() -> bool {
     std::env::var("CMD_LIB_DEBUG") == Ok("1".into())
 }
@@ -98,54 +265,482 @@ pub(crate) fn pipefail_enabled() -> bool {
 
 #[doc(hidden)]
 #[derive(Default)]
-pub struct GroupCmds {
-    group_cmds: Vec<Cmds>,
+pub struct GroupCmds<'a> {
+    group_cmds: Vec<Cmds<'a>>,
     current_dir: PathBuf,
+    deadline: Option<Deadline>,
+    kill_grace: Option<Duration>,
+    stderr_policy: Option<StderrPolicy>,
+    glob_policy: Option<GlobPolicy>,
+    piped_stdin: bool,
+    jobs: Vec<BackgroundJob>,
+    env: GroupEnv,
+}
+
+// environment variables set by "export" or removed by "unset", for the later commands in the
+// same group, without touching the environment of the current process
+#[derive(Clone, Default)]
+struct GroupEnv {
+    vars: BTreeMap<OsString, Option<OsString>>,
+    clear: bool, // not inheriting the environment of the current process
+}
+
+// changes of the environment by "#[env_clear]", "#[env_keep(keys)]" or "#[env_remove(keys)]"
+enum EnvChange {
+    Clear,
+    Keep(Vec<OsString>),
+    Remove(Vec<OsString>),
+}
+
+impl EnvChange {
+    fn keep<I, K>(keys: I) -> Self
+    where
+        I: IntoIterator<Item = K>,
+        K: AsRef<OsStr>,
+    {
+        EnvChange::Keep(keys.into_iter().map(|key| key.as_ref().into()).collect())
+    }
+
+    fn remove<I, K>(keys: I) -> Self
+    where
+        I: IntoIterator<Item = K>,
+        K: AsRef<OsStr>,
+    {
+        EnvChange::Remove(keys.into_iter().map(|key| key.as_ref().into()).collect())
+    }
 }
 
-impl GroupCmds {
-    pub fn append(mut self, cmds: Cmds) -> Self {
+impl GroupEnv {
+    // the variables set in the macro call are kept when clearing the environment, and the kept
+    // variables are the ones the commands would get before the change
+    fn change(&mut self, change: &EnvChange) {
+        match change {
+            EnvChange::Clear => {
+                self.clear = true;
+                self.vars.retain(|_, value| value.is_some());
+            }
+            EnvChange::Keep(keys) => {
+                let kept: Vec<_> = keys
+                    .iter()
+                    .filter_map(|key| Some((key.clone(), self.var(key)?)))
+                    .collect();
+                self.change(&EnvChange::Clear);
+                for (key, value) in kept {
+                    self.vars.insert(key, Some(value));
+                }
+            }
+            EnvChange::Remove(keys) => {
+                for key in keys {
+                    self.vars.insert(key.clone(), None);
+                }
+            }
+        }
+    }
+
+    fn apply(&self, cmd: &mut Command) {
+        if self.clear {
+            cmd.env_clear();
+        }
+        for (key, value) in self.vars.iter() {
+            match value {
+                Some(value) => cmd.env(key, value),
+                None => cmd.env_remove(key),
+            };
+        }
+    }
+
+    // "export KEY=value ..." or "unset KEY ..."
+    fn update(&mut self, args: &[OsString]) -> CmdResult {
+        let export = args[0] == EXPORT_CMD;
+        for arg in args[1..].iter() {
+            let (key, value) = match arg.as_bytes().iter().position(|&c| c == b'=') {
+                Some(pos) if export => (&arg.as_bytes()[..pos], Some(&arg.as_bytes()[pos + 1..])),
+                None if !export => (arg.as_bytes(), None),
+                _ => (&b""[..], None),
+            };
+            if key.is_empty()
+                || key[0].is_ascii_digit()
+                || !key.iter().all(|c| c.is_ascii_alphanumeric() || *c == b'_')
+            {
+                let err_msg = format!(
+                    "{}: invalid argument: {}",
+                    args[0].to_string_lossy(),
+                    arg.to_string_lossy()
+                );
+                return Err(Error::new(ErrorKind::InvalidInput, err_msg));
+            }
+            self.vars.insert(
+                OsStr::from_bytes(key).to_os_string(),
+                value.map(|v| OsStr::from_bytes(v).to_os_string()),
+            );
+        }
+        Ok(())
+    }
+
+    // the value of an environment variable, as the commands in the group would get
+    fn var<K: AsRef<OsStr>>(&self, name: K) -> Option<OsString> {
+        match self.vars.get(name.as_ref()) {
+            Some(value) => value.clone(),
+            None if self.clear => None,
+            None => std::env::var_os(name),
+        }
+    }
+
+    // the full environment for builtin commands, as external commands would get
+    fn builtin_vars(&self, cmd_vars: HashMap<String, String>) -> HashMap<OsString, OsString> {
+        let mut vars: HashMap<OsString, OsString> = if self.clear {
+            HashMap::new()
+        } else {
+            std::env::vars_os().collect()
+        };
+        for (key, value) in self.vars.iter() {
+            match value {
+                Some(value) => vars.insert(key.clone(), value.clone()),
+                None => vars.remove(key),
+            };
+        }
+        vars.extend(
+            cmd_vars
+                .into_iter()
+                .map(|(key, value)| (key.into(), value.into())),
+        );
+        vars
+    }
+}
+
+// stdio of a subshell, which is inherited by the commands inside
+struct SubshellIo {
+    stdin: CmdIn,
+    stdout: CmdOut,
+    stderr: CmdOut,
+}
+
+// settings of the outer commands, which also apply to the commands nested in substitutions
+// or subshells
+struct Inherited {
+    deadline: Option<Deadline>,
+    kill_grace: Option<Duration>,
+    stderr_policy: Option<StderrPolicy>,
+    glob_policy: Option<GlobPolicy>,
+    cancelled: Option<Arc<AtomicBool>>,
+}
+
+struct BackgroundJob {
+    full_cmds: String,
+    ignore_error: bool,
+    children: CmdChildren,
+}
+
+impl<'a> GroupCmds<'a> {
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.deadline = Some(Deadline::new(timeout));
+        self
+    }
+
+    pub fn kill_grace(mut self, kill_grace: Duration) -> Self {
+        self.kill_grace = Some(kill_grace);
+        self
+    }
+
+    pub fn stderr(mut self, policy: StderrPolicy) -> Self {
+        self.stderr_policy = Some(policy);
+        self
+    }
+
+    pub fn glob(mut self, policy: GlobPolicy) -> Self {
+        self.glob_policy = Some(policy);
+        self
+    }
+
+    pub fn piped_stdin(mut self) -> Self {
+        self.piped_stdin = true;
+        self
+    }
+
+    pub fn env_clear(mut self) -> Self {
+        self.env.change(&EnvChange::Clear);
+        self
+    }
+
+    pub fn env_keep<I, K>(mut self, keys: I) -> Self
+    where
+        I: IntoIterator<Item = K>,
+        K: AsRef<OsStr>,
+    {
+        self.env.change(&EnvChange::keep(keys));
+        self
+    }
+
+    pub fn env_remove<I, K>(mut self, keys: I) -> Self
+    where
+        I: IntoIterator<Item = K>,
+        K: AsRef<OsStr>,
+    {
+        self.env.change(&EnvChange::remove(keys));
+        self
+    }
+
+    pub fn and(self, mut cmds: Cmds<'a>) -> Self {
+        cmds.connector = Connector::And;
+        self.append(cmds)
+    }
+
+    pub fn or(self, mut cmds: Cmds<'a>) -> Self {
+        cmds.connector = Connector::Or;
+        self.append(cmds)
+    }
+
+    pub fn append(mut self, mut cmds: Cmds<'a>) -> Self {
+        cmds.deadline = self.deadline;
+        cmds.kill_grace = self.kill_grace;
+        cmds.stderr_policy = self.stderr_policy.clone();
+        cmds.glob_policy = self.glob_policy;
+        cmds.piped_stdin = self.piped_stdin;
         self.group_cmds.push(cmds);
         self
     }
 
+    // like "$?" in bash, the last list returns the result of its last commands which ran
     pub fn run_cmd(&mut self) -> CmdResult {
+        match self.run_cmds(Connector::Seq)? {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    fn inherit(&mut self, inherited: &Inherited) {
+        self.deadline = inherited.deadline;
+        self.kill_grace = inherited.kill_grace;
+        self.stderr_policy = inherited.stderr_policy.clone();
+        self.glob_policy = inherited.glob_policy;
         for cmds in self.group_cmds.iter_mut() {
-            if let Err(e) = cmds.run_cmd(&mut self.current_dir) {
-                if !cmds.ignore_error {
-                    return Err(e);
+            cmds.deadline = inherited.deadline;
+            cmds.kill_grace = inherited.kill_grace;
+            cmds.stderr_policy = inherited.stderr_policy.clone();
+            cmds.glob_policy = inherited.glob_policy;
+            cmds.subshell_cancelled = inherited.cancelled.clone();
+        }
+    }
+
+    fn full_cmds(&self) -> String {
+        let full_cmds: Vec<&str> = self
+            .group_cmds
+            .iter()
+            .map(|cmds| cmds.get_full_cmds())
+            .collect();
+        full_cmds.join("; ")
+    }
+
+    // run as a subshell, with its own current directory, environment and stdio, killing the
+    // running commands once it is cancelled
+    fn run_subshell(
+        mut self,
+        current_dir: PathBuf,
+        env: GroupEnv,
+        io: SubshellIo,
+        cancelled: Arc<AtomicBool>,
+    ) -> CmdResult {
+        self.current_dir = current_dir;
+        self.env = env;
+        let io = Arc::new(io);
+        for cmds in self.group_cmds.iter_mut() {
+            cmds.subshell_io = Some(io.clone());
+            cmds.subshell_cancelled = Some(cancelled.clone());
+        }
+        self.run_cmd()
+    }
+
+    // run all the commands, returning the failure of the last commands which ran, which did not
+    // stop the group, for the following commands connected by `next`
+    //
+    // Like "set -e" in bash, a failure only stops the group when it comes from the last commands
+    // in a "&&" or "||" list.
+    fn run_cmds(&mut self, next: Connector) -> Result<Option<Error>> {
+        let mut last_err = None;
+        let len = self.group_cmds.len();
+        for i in 0..len {
+            let list_end = if i + 1 < len {
+                self.group_cmds[i + 1].connector == Connector::Seq
+            } else {
+                next == Connector::Seq
+            };
+            let cmds = &mut self.group_cmds[i];
+            if cmds.connector == Connector::Seq {
+                last_err = None;
+            }
+            if !cmds.connector.should_run(last_err.is_none()) {
+                continue;
+            }
+            let ret = match Self::run_in_group(
+                &mut self.jobs,
+                &mut self.env,
+                cmds,
+                &mut self.current_dir,
+            ) {
+                Some(ret) => ret,
+                None => cmds.run_cmd(&mut self.current_dir, &self.env),
+            };
+            match ret {
+                Err(e) if !cmds.ignore_error => {
+                    if list_end {
+                        return Err(e);
+                    }
+                    last_err = Some(e);
                 }
+                _ => last_err = None,
             }
         }
-        Ok(())
+        Ok(last_err)
     }
 
     pub fn run_fun(&mut self) -> FunResult {
+        let output = self.run_fun_bytes()?;
+        let mut s = String::from_utf8_lossy(&output).to_string();
+        if s.ends_with('\n') {
+            s.pop();
+        }
+        Ok(s)
+    }
+
+    pub fn run_fun_bytes(&mut self) -> Result<Vec<u8>> {
+        // the output of all the commands in the last "&&" or "||" list is captured
+        let start = self
+            .group_cmds
+            .iter()
+            .rposition(|cmds| cmds.connector == Connector::Seq)
+            .unwrap_or(0);
+        let mut last_list = self.group_cmds.split_off(start);
+        // run previous commands
+        self.run_cmds(Connector::Seq)?;
+        // run last function commands, keeping the raw output
+        let mut output = vec![];
+        let mut last_err = None;
+        let len = last_list.len();
+        for (i, cmds) in last_list.iter_mut().enumerate() {
+            if !cmds.connector.should_run(last_err.is_none()) {
+                continue;
+            }
+            let ret = match Self::run_in_group(
+                &mut self.jobs,
+                &mut self.env,
+                cmds,
+                &mut self.current_dir,
+            ) {
+                Some(ret) => ret.map(|_| vec![]),
+                None => cmds.run_fun_bytes(&mut self.current_dir, &self.env),
+            };
+            match ret {
+                Ok(out) => {
+                    output.extend(out);
+                    last_err = None;
+                }
+                Err(_) if cmds.ignore_error => last_err = None,
+                Err(e) => {
+                    if i + 1 == len {
+                        return Err(e);
+                    }
+                    last_err = Some(e);
+                }
+            }
+        }
+        match last_err {
+            Some(e) => Err(e),
+            None => Ok(output),
+        }
+    }
+
+    // background jobs, "wait", "export" and "unset" are handled by the group, returns `None` for
+    // other commands
+    fn run_in_group(
+        jobs: &mut Vec<BackgroundJob>,
+        env: &mut GroupEnv,
+        cmds: &mut Cmds<'a>,
+        current_dir: &mut PathBuf,
+    ) -> Option<CmdResult> {
+        if cmds.background {
+            // "!" is applied when the job is waited, unless it failed to spawn
+            return Some(match cmds.spawn(current_dir, env, false) {
+                Ok(children) => {
+                    jobs.push(BackgroundJob {
+                        full_cmds: cmds.full_cmds.clone(),
+                        ignore_error: cmds.ignore_error,
+                        children,
+                    });
+                    Ok(())
+                }
+                Err(e) => cmds.negate_result(Err(e)),
+            });
+        }
+        if cmds.is_wait() {
+            // wait for all the jobs, and report the first failure
+            let mut ret = Ok(());
+            for mut job in jobs.drain(..) {
+                let job_ret = job.children.wait();
+                if ret.is_ok() && !job.ignore_error {
+                    ret = job_ret;
+                }
+            }
+            return Some(ret);
+        }
+        let inherited = cmds.inherited();
+        if let Some(cmd) = cmds.env_cmd() {
+            let ret = cmd
+                .resolve_subst_args(current_dir, env, &inherited)
+                .and_then(|_| env.update(&cmd.args_without_ignore()));
+            return Some(cmds.negate_result(ret));
+        }
+        None
+    }
+
+    pub fn run_statuses(&mut self) -> Result<Vec<ExitStatus>> {
         // run previous commands
         let mut last_cmd = self.group_cmds.pop().unwrap();
-        self.run_cmd()?;
-        // run last function command
-        let ret = last_cmd.run_fun(&mut self.current_dir);
-        if ret.is_err() && last_cmd.ignore_error {
-            return Ok("".into());
+        let last_err = self.run_cmds(last_cmd.connector)?;
+        if !last_cmd.connector.should_run(last_err.is_none()) {
+            return Ok(vec![]);
         }
-        ret
+        // run last pipeline and collect all the statuses
+        last_cmd
+            .spawn(&mut self.current_dir, &self.env, false)?
+            .wait_statuses()
+    }
+
+    pub fn run_fun_full(&mut self) -> Result<Output> {
+        // run previous commands
+        let mut last_cmd = self.group_cmds.pop().unwrap();
+        let last_err = self.run_cmds(last_cmd.connector)?;
+        if !last_cmd.connector.should_run(last_err.is_none()) {
+            // the status of the last commands which ran, like "$?" in bash
+            let status = match last_err {
+                Some(e) => CmdError::from_io_error(&e)
+                    .and_then(CmdError::status)
+                    .unwrap_or_else(|| ExitStatus::from_raw(1 << 8)),
+                None => ExitStatus::from_raw(0),
+            };
+            return Ok(Output {
+                status,
+                stdout: vec![],
+                stderr: vec![],
+            });
+        }
+        // run last pipeline, capturing both stdout and stderr
+        let captured = Arc::new(Mutex::new(vec![]));
+        last_cmd.stderr_policy = Some(StderrPolicy::Capture(captured.clone()));
+        let mut output = last_cmd
+            .spawn(&mut self.current_dir, &self.env, true)?
+            .into_fun_children()
+            .wait_with_full_output()?;
+        // stderr of the command substitutions, which run before the pipeline
+        let mut stderr = std::mem::take(&mut *captured.lock().unwrap());
+        stderr.append(&mut output.stderr);
+        output.stderr = stderr;
+        Ok(output)
     }
 
     pub fn spawn(mut self, with_output: bool) -> Result<CmdChildren> {
         assert_eq!(self.group_cmds.len(), 1);
         let mut cmds = self.group_cmds.pop().unwrap();
-        let ret = cmds.spawn(&mut self.current_dir, with_output);
-        // spawning error contains no command information, attach it here
-        if let Err(ref e) = ret {
-            if !cmds.ignore_error {
-                return Err(Error::new(
-                    e.kind(),
-                    format!("Spawning {} failed: {}", cmds.get_full_cmds(), e),
-                ));
-            }
-        }
-        ret
+        cmds.spawn(&mut self.current_dir, &self.env, with_output)
     }
 
     pub fn spawn_with_output(self) -> Result<FunChildren> {
@@ -153,21 +748,69 @@ impl GroupCmds {
     }
 }
 
+// the background jobs not waited by "wait" are waited here, so no children are left behind
+impl Drop for GroupCmds<'_> {
+    fn drop(&mut self) {
+        for mut job in self.jobs.drain(..) {
+            if let Err(e) = job.children.wait() {
+                if !job.ignore_error {
+                    warn!("Background job {} failed: {}", job.full_cmds, e);
+                }
+            }
+        }
+    }
+}
+
 #[doc(hidden)]
 #[derive(Default)]
-pub struct Cmds {
+pub struct Cmds<'a> {
     cmds: Vec<Option<Cmd>>,
     full_cmds: String,
     ignore_error: bool,
+    deadline: Option<Deadline>,
+    kill_grace: Option<Duration>,
+    stderr_policy: Option<StderrPolicy>,
+    glob_policy: Option<GlobPolicy>,
+    piped_stdin: bool,
+    stdout_buffer: Option<(&'a mut dyn OutputBuffer, bool)>,
+    connector: Connector,
+    negate: bool,
+    background: bool,
+    // for process substitution
+    pipe_in: Option<PipeReader>,
+    pipe_out: Option<PipeWriter>,
+    subshell_io: Option<Arc<SubshellIo>>,
+    subshell_cancelled: Option<Arc<AtomicBool>>,
+    env_changes: Vec<EnvChange>,
 }
 
-impl Cmds {
+// how the commands are connected with the previous ones
+#[derive(Clone, Copy, Default, PartialEq)]
+enum Connector {
+    #[default]
+    Seq,
+    And,
+    Or,
+}
+
+impl Connector {
+    // whether to run the commands, according to the result of the previous ones
+    fn should_run(self, last_ok: bool) -> bool {
+        match self {
+            Connector::Seq => true,
+            Connector::And => last_ok,
+            Connector::Or => !last_ok,
+        }
+    }
+}
+
+impl<'a> Cmds<'a> {
     pub fn pipe(mut self, cmd: Cmd) -> Self {
         if !self.full_cmds.is_empty() {
             self.full_cmds += " | ";
         }
         self.full_cmds += &cmd.cmd_str();
-        let (ignore_error, cmd) = cmd.gen_command();
+        let (ignore_error, negate, cmd) = cmd.gen_command();
         if ignore_error {
             if self.cmds.is_empty() {
                 // first command in the pipe
@@ -176,66 +819,270 @@ impl Cmds {
                 warn!("Builtin \"ignore\" command at wrong position");
             }
         }
+        if negate {
+            if self.cmds.is_empty() {
+                self.negate = true;
+            } else {
+                warn!("Negation \"!\" at wrong position");
+            }
+        }
         self.cmds.push(Some(cmd));
         self
     }
 
+    pub fn background(mut self) -> Self {
+        self.background = true;
+        self
+    }
+
+    pub fn env_clear(mut self) -> Self {
+        self.env_changes.push(EnvChange::Clear);
+        self
+    }
+
+    pub fn env_keep<I, K>(mut self, keys: I) -> Self
+    where
+        I: IntoIterator<Item = K>,
+        K: AsRef<OsStr>,
+    {
+        self.env_changes.push(EnvChange::keep(keys));
+        self
+    }
+
+    pub fn env_remove<I, K>(mut self, keys: I) -> Self
+    where
+        I: IntoIterator<Item = K>,
+        K: AsRef<OsStr>,
+    {
+        self.env_changes.push(EnvChange::remove(keys));
+        self
+    }
+
+    // "wait" for the background jobs in the group
+    fn is_wait(&self) -> bool {
+        matches!(self.cmds.as_slice(), [Some(cmd)] if cmd.args_without_ignore() == [WAIT_CMD])
+    }
+
+    // "export" or "unset" to update the environment of the group
+    fn env_cmd(&mut self) -> Option<&mut Cmd> {
+        match self.cmds.as_mut_slice() {
+            [Some(cmd)] if cmd.arg0() == EXPORT_CMD || cmd.arg0() == UNSET_CMD => Some(cmd),
+            _ => None,
+        }
+    }
+
+    pub fn stdout_to_buffer(mut self, buffer: &'a mut dyn OutputBuffer, append: bool) -> Self {
+        self.full_cmds += if append {
+            " >> [buffer]"
+        } else {
+            " > [buffer]"
+        };
+        self.stdout_buffer = Some((buffer, append));
+        self
+    }
+
+    fn inherited(&self) -> Inherited {
+        Inherited {
+            deadline: self.deadline,
+            kill_grace: self.kill_grace,
+            stderr_policy: self.stderr_policy.clone(),
+            glob_policy: self.glob_policy,
+            cancelled: self.subshell_cancelled.clone(),
+        }
+    }
+
     fn get_full_cmds(&self) -> &str {
         &self.full_cmds
     }
 
-    fn spawn(&mut self, current_dir: &mut PathBuf, with_output: bool) -> Result<CmdChildren> {
+    fn spawn(
+        &mut self,
+        current_dir: &mut PathBuf,
+        env: &GroupEnv,
+        with_output: bool,
+    ) -> Result<CmdChildren> {
+        if self.stdout_buffer.is_some() {
+            let err_msg = format!(
+                "{}: redirecting to buffer is not supported here",
+                self.full_cmds
+            );
+            return Err(Error::new(ErrorKind::Other, err_msg));
+        }
+        if let Some(ref cancelled) = self.subshell_cancelled {
+            if cancelled.load(Ordering::SeqCst) {
+                let err_msg = format!("{}: subshell cancelled", self.full_cmds);
+                return Err(Error::new(ErrorKind::Interrupted, err_msg));
+            }
+        }
         if debug_enabled() {
             debug!("Running {} ...", self.get_full_cmds());
         }
+        // the environment changes only for these commands
+        let cmds_env;
+        let env = if self.env_changes.is_empty() {
+            env
+        } else {
+            let mut changed = env.clone();
+            for change in self.env_changes.iter() {
+                changed.change(change);
+            }
+            cmds_env = changed;
+            &cmds_env
+        };
 
         // spawning all the sub-processes
+        let stderr_policy = if self.subshell_io.is_some() {
+            StderrPolicy::Inherit
+        } else {
+            self.stderr_policy.clone().unwrap_or_else(stderr_policy)
+        };
         let mut children: Vec<Result<CmdChild>> = Vec::new();
+        let mut proc_substs = vec![];
         let len = self.cmds.len();
-        let mut prev_pipe_in = None;
+        let mut prev_pipe_in = self.pipe_in.take();
+        let mut stdin = None;
+        if self.piped_stdin {
+            // the first command reads from the pipe, unless it is redirected
+            let (pipe_reader, pipe_writer) = os_pipe::pipe()?;
+            prev_pipe_in = Some(pipe_reader);
+            stdin = Some(pipe_writer);
+        }
+        let inherited = self.inherited();
+        for cmd in self.cmds.iter_mut().flatten() {
+            cmd.resolve_subst_args(current_dir, env, &inherited)?;
+        }
         for (i, cmd_opt) in self.cmds.iter_mut().enumerate() {
             let mut cmd = cmd_opt.take().unwrap();
+            let info = CmdInfo::new(cmd.cmd_str(), cmd.args_without_ignore(), i, current_dir);
+            proc_substs.extend(cmd.spawn_proc_substs(current_dir, env)?);
+            if let Some(ref mut group) = cmd.subshell {
+                group.inherit(&inherited);
+            }
+            if let Some(io) = self.subshell_io.as_ref() {
+                cmd.inherit_subshell_io(io, i == 0, i == len - 1)
+                    .map_err(|e| info.io_error(e, true))?;
+            }
             if i != len - 1 {
                 // not the last, update redirects
                 let (pipe_reader, pipe_writer) = os_pipe::pipe()?;
-                cmd.setup_redirects(&mut prev_pipe_in, Some(pipe_writer), with_output)?;
+                cmd.setup_redirects(
+                    &mut prev_pipe_in,
+                    Some(pipe_writer),
+                    with_output,
+                    &stderr_policy,
+                )
+                .map_err(|e| info.io_error(e, true))?;
                 prev_pipe_in = Some(pipe_reader);
             } else {
-                cmd.setup_redirects(&mut prev_pipe_in, None, with_output)?;
+                cmd.setup_redirects(
+                    &mut prev_pipe_in,
+                    self.pipe_out.take(),
+                    with_output,
+                    &stderr_policy,
+                )
+                .map_err(|e| info.io_error(e, true))?;
             }
-            let child = cmd.spawn(current_dir, with_output);
+            let child = cmd.spawn(info, current_dir, env, with_output);
             children.push(child);
         }
 
-        Ok(CmdChildren::new(children, self.ignore_error))
+        let mut children = CmdChildren::new(children, proc_substs, self.ignore_error);
+        if self.negate {
+            children.set_negate(&self.full_cmds);
+        }
+        children.set_timeout(self.deadline, self.kill_grace);
+        children.set_cancelled(self.subshell_cancelled.clone());
+        children.set_stdin(stdin);
+        Ok(children)
     }
 
-    fn spawn_with_output(&mut self, current_dir: &mut PathBuf) -> Result<FunChildren> {
-        self.spawn(current_dir, true)
+    fn spawn_with_output(
+        &mut self,
+        current_dir: &mut PathBuf,
+        env: &GroupEnv,
+    ) -> Result<FunChildren> {
+        self.spawn(current_dir, env, true)
             .map(CmdChildren::into_fun_children)
     }
 
-    fn run_cmd(&mut self, current_dir: &mut PathBuf) -> CmdResult {
-        self.spawn(current_dir, false)?.wait()
+    // the spawned children take care of "!", only the spawning errors are negated here
+    fn run_cmd(&mut self, current_dir: &mut PathBuf, env: &GroupEnv) -> CmdResult {
+        if let Some((buffer, append)) = self.stdout_buffer.take() {
+            match self.spawn_with_output(current_dir, env) {
+                Ok(mut children) => children
+                    .wait_with_raw_output()
+                    .map(|output| buffer.write_output(output, append)),
+                Err(e) => self.negate_result(Err(e)),
+            }
+        } else {
+            match self.spawn(current_dir, env, false) {
+                Ok(mut children) => children.wait(),
+                Err(e) => self.negate_result(Err(e)),
+            }
+        }
     }
 
-    fn run_fun(&mut self, current_dir: &mut PathBuf) -> FunResult {
-        self.spawn_with_output(current_dir)?.wait_with_output()
+    #[cfg(test)]
+    fn run_fun(&mut self, current_dir: &mut PathBuf, env: &GroupEnv) -> FunResult {
+        self.spawn_with_output(current_dir, env)?.wait_with_output()
+    }
+
+    fn run_fun_bytes(&mut self, current_dir: &mut PathBuf, env: &GroupEnv) -> Result<Vec<u8>> {
+        if self.stdout_buffer.is_some() {
+            return self.run_cmd(current_dir, env).map(|_| vec![]);
+        }
+        match self.spawn_with_output(current_dir, env) {
+            Ok(mut children) => children.wait_with_raw_output(),
+            Err(e) => self.negate_result(Err(e)),
+        }
+    }
+
+    fn negate_result<T: Default>(&self, ret: Result<T>) -> Result<T> {
+        child::negate_result(self.negate.then_some(self.full_cmds.as_str()), ret)
+    }
+}
+
+#[doc(hidden)]
+pub trait OutputBuffer: Send {
+    fn write_output(&mut self, output: Vec<u8>, append: bool);
+}
+
+impl OutputBuffer for Vec<u8> {
+    fn write_output(&mut self, output: Vec<u8>, append: bool) {
+        if !append {
+            self.clear();
+        }
+        self.extend(output);
+    }
+}
+
+impl OutputBuffer for String {
+    fn write_output(&mut self, output: Vec<u8>, append: bool) {
+        if !append {
+            self.clear();
+        }
+        self.push_str(&String::from_utf8_lossy(&output));
     }
 }
 
 #[doc(hidden)]
 pub enum Redirect {
     FileToStdin(PathBuf),
+    DataToStdin(Vec<u8>),
     StdoutToStderr,
     StderrToStdout,
     StdoutToFile(PathBuf, bool),
     StderrToFile(PathBuf, bool),
+    FdToFile(i32, PathBuf, bool),
+    FileToFd(i32, PathBuf, bool),
+    FdToFd(i32, i32),
+    CloseFd(i32),
 }
 impl fmt::Debug for Redirect {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Redirect::FileToStdin(path) => f.write_str(&format!("< {}", path.display())),
+            Redirect::DataToStdin(data) => f.write_str(&format!("<< [{} bytes]", data.len())),
             Redirect::StdoutToStderr => f.write_str(">&2"),
             Redirect::StderrToStdout => f.write_str("2>&1"),
             Redirect::StdoutToFile(path, append) => {
@@ -252,6 +1099,22 @@ impl fmt::Debug for Redirect {
                     f.write_str(&format!("2> {}", path.display()))
                 }
             }
+            Redirect::FdToFile(fd, path, append) => {
+                if *append {
+                    f.write_str(&format!("{}>> {}", fd, path.display()))
+                } else {
+                    f.write_str(&format!("{}> {}", fd, path.display()))
+                }
+            }
+            Redirect::FileToFd(fd, path, rw) => {
+                if *rw {
+                    f.write_str(&format!("{}<> {}", fd, path.display()))
+                } else {
+                    f.write_str(&format!("{}< {}", fd, path.display()))
+                }
+            }
+            Redirect::FdToFd(fd1, fd2) => f.write_str(&format!("{}>&{}", fd1, fd2)),
+            Redirect::CloseFd(fd) => f.write_str(&format!("{}>&-", fd)),
         }
     }
 }
@@ -271,6 +1134,20 @@ pub struct Cmd {
     stderr_redirect: Option<CmdOut>,
     stdout_logging: Option<PipeReader>,
     stderr_logging: Option<PipeReader>,
+    stderr_policy: StderrPolicy,
+    proc_substs: Vec<Result<ProcSubst>>,
+    subst_fds: Vec<File>,
+    subst_args: Vec<(usize, SubstArg, bool)>, // position, argument, split?
+    fd_redirects: BTreeMap<i32, Option<File>>, // other fds, or stdio to be closed
+    subshell: Option<GroupCmds<'static>>,
+}
+
+// process substitution "<(cmds)" or ">(cmds)", connected by a pipe passed as "/dev/fd/N"
+struct ProcSubst {
+    cmds: GroupCmds<'static>,
+    reader: PipeReader,
+    writer: PipeWriter,
+    input: bool,
 }
 
 impl Default for Cmd {
@@ -286,6 +1163,12 @@ impl Default for Cmd {
             stderr_redirect: None,
             stdout_logging: None,
             stderr_logging: None,
+            stderr_policy: StderrPolicy::default(),
+            proc_substs: vec![],
+            subst_fds: vec![],
+            subst_args: vec![],
+            fd_redirects: BTreeMap::new(),
+            subshell: None,
         }
     }
 }
@@ -296,7 +1179,7 @@ impl Cmd {
         O: AsRef<OsStr>,
     {
         let arg_str = arg.as_ref().to_string_lossy().to_string();
-        if arg_str != IGNORE_CMD && !self.args.iter().any(|cmd| *cmd != IGNORE_CMD) {
+        if !is_prefix(arg.as_ref()) && self.args.iter().all(|cmd| is_prefix(cmd)) {
             let v: Vec<&str> = arg_str.split('=').collect();
             if v.len() == 2 && v[0].chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
                 self.vars.insert(v[0].into(), v[1].into());
@@ -319,13 +1202,123 @@ impl Cmd {
         self
     }
 
+    pub fn add_subst_arg(mut self, arg: SubstArg, split: bool) -> Self {
+        if self.args.iter().all(|cmd| is_prefix(cmd)) {
+            // the command name is unknown until resolved
+            self.in_cmd_map = false;
+        }
+        let placeholder = arg.to_string();
+        self.subst_args.push((self.args.len(), arg, split));
+        self.args.push(placeholder.into());
+        self
+    }
+
+    pub fn subshell(mut self, group: GroupCmds<'static>) -> Self {
+        self.in_cmd_map = false;
+        self.subshell = Some(group);
+        self
+    }
+
     pub fn add_redirect(mut self, redirect: Redirect) -> Self {
         self.redirects.push(redirect);
         self
     }
 
+    pub fn add_proc_subst(mut self, cmds: GroupCmds<'static>, input: bool) -> Self {
+        match os_pipe::pipe() {
+            Ok((reader, writer)) => {
+                let fd = if input {
+                    reader.as_raw_fd()
+                } else {
+                    writer.as_raw_fd()
+                };
+                self = self.add_arg(format!("/dev/fd/{}", fd));
+                self.proc_substs.push(Ok(ProcSubst {
+                    cmds,
+                    reader,
+                    writer,
+                    input,
+                }));
+            }
+            Err(e) => self.proc_substs.push(Err(e)),
+        }
+        self
+    }
+
+    // spawn the process substitutions, keeping the other ends of the pipes for this command
+    fn spawn_proc_substs(
+        &mut self,
+        current_dir: &Path,
+        env: &GroupEnv,
+    ) -> Result<Vec<Result<CmdChild>>> {
+        let mut children = vec![];
+        for proc_subst in self.proc_substs.drain(..) {
+            let ProcSubst {
+                mut cmds,
+                reader,
+                writer,
+                input,
+            } = proc_subst?;
+            let mut inner = cmds.group_cmds.pop().unwrap();
+            // safe, since the fds are owned by the pipes
+            let fd = if input {
+                inner.pipe_out = Some(writer);
+                unsafe { File::from_raw_fd(reader.into_raw_fd()) }
+            } else {
+                inner.pipe_in = Some(reader);
+                unsafe { File::from_raw_fd(writer.into_raw_fd()) }
+            };
+            self.subst_fds.push(fd);
+            let inner_children = inner.spawn(&mut current_dir.to_path_buf(), env, false)?;
+            children.extend(inner_children.into_children());
+        }
+        Ok(children)
+    }
+
+    // run the command substitutions, and replace the placeholders with their outputs
+    fn resolve_subst_args(
+        &mut self,
+        current_dir: &Path,
+        env: &GroupEnv,
+        inherited: &Inherited,
+    ) -> CmdResult {
+        if self.subst_args.is_empty() {
+            return Ok(());
+        }
+        let mut subst_args = std::mem::take(&mut self.subst_args).into_iter().peekable();
+        let mut args = vec![];
+        for (i, arg) in std::mem::take(&mut self.args).into_iter().enumerate() {
+            match subst_args.next_if(|(pos, _, _)| *pos == i) {
+                Some((_, subst_arg, split)) => {
+                    args.extend(subst_arg.resolve(current_dir, env, inherited, split)?)
+                }
+                None => args.push(arg),
+            }
+        }
+        self.args = args;
+
+        let arg0 = self.arg0();
+        if arg0.is_empty() {
+            let err_msg = format!("empty command after substitution: {}", self.cmd_str());
+            return Err(Error::new(ErrorKind::Other, err_msg));
+        }
+        self.in_cmd_map = CMD_MAP.lock().unwrap().contains_key(&arg0);
+        if !self.in_cmd_map {
+            self.build_std_cmd();
+        }
+        Ok(())
+    }
+
+    fn args_without_ignore(&self) -> Vec<OsString> {
+        self.args
+            .iter()
+            .skip_while(|cmd| is_prefix(cmd))
+            .cloned()
+            .collect()
+    }
+
     fn arg0(&self) -> OsString {
-        let mut args = self.args.iter().skip_while(|cmd| *cmd == IGNORE_CMD);
+        let mut args = self.args.iter().skip_while(|cmd| is_prefix(cmd));
         if let Some(arg) = args.next() {
             return arg.into();
         }
@@ -333,7 +1326,10 @@ impl Cmd {
     }
 
     fn cmd_str(&self) -> String {
-        let mut ret = format!("{:?}", self.args);
+        let mut ret = match self.subshell {
+            Some(ref group) => format!("({})", group.full_cmds()),
+            None => format!("{:?}", self.args),
+        };
         let mut extra = String::new();
         if !self.vars.is_empty() {
             extra += &format!("{:?}", self.vars);
@@ -350,83 +1346,123 @@ impl Cmd {
         ret
     }
 
-    fn gen_command(mut self) -> (bool, Self) {
-        let args: Vec<OsString> = self
-            .args
-            .iter()
-            .skip_while(|cmd| *cmd == IGNORE_CMD)
-            .map(|s| s.into())
-            .collect();
-        if !self.in_cmd_map {
-            let mut cmd = Command::new(&args[0]);
-            cmd.args(&args[1..]);
-            for (k, v) in self.vars.iter() {
-                cmd.env(k, v);
-            }
-            self.std_cmd = Some(cmd);
+    // returns whether it is prefixed by "ignore" and "!", and the generated command
+    fn gen_command(mut self) -> (bool, bool, Self) {
+        let prefixes = &self.args[..self.args.iter().take_while(|cmd| is_prefix(cmd)).count()];
+        let ignore_error = prefixes.iter().any(|cmd| cmd == IGNORE_CMD);
+        let negate = prefixes.iter().any(|cmd| cmd == NEGATE_CMD);
+        if !self.in_cmd_map && self.subshell.is_none() {
+            self.build_std_cmd();
         }
-        (self.args.len() > args.len(), self)
+        (ignore_error, negate, self)
     }
 
-    fn spawn(mut self, current_dir: &mut PathBuf, with_output: bool) -> Result<CmdChild> {
+    fn build_std_cmd(&mut self) {
+        let args = self.args_without_ignore();
+        let mut cmd = Command::new(&args[0]);
+        cmd.args(&args[1..]);
+        self.std_cmd = Some(cmd);
+    }
+
+    fn spawn(
+        mut self,
+        info: CmdInfo,
+        current_dir: &mut PathBuf,
+        env: &GroupEnv,
+        with_output: bool,
+    ) -> Result<CmdChild> {
         let arg0 = self.arg0();
+        if arg0 == CD_CMD || self.subshell.is_some() || self.in_cmd_map {
+            self.check_fd_redirects()
+                .map_err(|e| info.io_error(e, true))?;
+        }
         if arg0 == CD_CMD {
-            let child = self.run_cd_cmd(current_dir)?;
+            self.run_cd_cmd(current_dir)
+                .map_err(|e| info.io_error(e, false))?;
+            Ok(CmdChild::new(
+                CmdChildHandle::SyncFn(()),
+                info,
+                self.stdout_logging,
+                self.stderr_logging,
+                self.stderr_policy,
+            ))
+        } else if let Some(group) = self.subshell.take() {
+            let current_dir = current_dir.clone();
+            let env = env.clone();
+            let io = SubshellIo {
+                stdin: self.take_stdin()?,
+                stdout: self.take_stdout()?,
+                stderr: self.take_stderr()?,
+            };
+            let cancelled = Arc::new(AtomicBool::new(false));
+            let subshell_cancelled = cancelled.clone();
+            let handle = thread::Builder::new()
+                .spawn(move || group.run_subshell(current_dir, env, io, subshell_cancelled))
+                .map_err(|e| info.io_error(e, true))?;
             Ok(CmdChild::new(
-                CmdChildHandle::SyncFn(child),
-                self.cmd_str(),
+                CmdChildHandle::Thread(handle, cancelled),
+                info,
                 self.stdout_logging,
                 self.stderr_logging,
+                self.stderr_policy,
             ))
         } else if self.in_cmd_map {
-            let cmd_str = self.cmd_str();
             let pipe_out = self.stdout_logging.is_none();
+            let cancelled = Arc::new(AtomicBool::new(false));
+            let stdin = self.take_stdin()?;
+            let stdout = self.take_stdout()?;
+            let stderr = self.take_stderr()?;
+            let args_os = self.args_without_ignore();
+            let vars_os = env.builtin_vars(self.vars);
             let mut env = CmdEnv {
-                args: self
-                    .args
-                    .into_iter()
-                    .skip_while(|cmd| *cmd == IGNORE_CMD)
+                args: args_os
+                    .iter()
                     .map(|s| s.to_string_lossy().to_string())
                     .collect(),
-                vars: self.vars,
+                args_os,
+                vars: vars_os
+                    .iter()
+                    .filter_map(|(key, value)| {
+                        Some((key.to_str()?.to_string(), value.to_str()?.to_string()))
+                    })
+                    .collect(),
+                vars_os,
                 current_dir: if current_dir.as_os_str().is_empty() {
                     std::env::current_dir()?
                 } else {
                     current_dir.clone()
                 },
-                stdin: if let Some(redirect_in) = self.stdin_redirect.take() {
-                    redirect_in
-                } else {
-                    CmdIn::Pipe(os_pipe::dup_stdin()?)
-                },
-                stdout: if let Some(redirect_out) = self.stdout_redirect.take() {
-                    redirect_out
-                } else {
-                    CmdOut::Pipe(os_pipe::dup_stdout()?)
-                },
-                stderr: if let Some(redirect_err) = self.stderr_redirect.take() {
-                    redirect_err
-                } else {
-                    CmdOut::Pipe(os_pipe::dup_stderr()?)
-                },
+                stdin,
+                stdout,
+                stderr,
+                cancelled: cancelled.clone(),
             };
 
-            let internal_cmd = CMD_MAP.lock().unwrap()[&arg0];
+            let internal_cmd = CMD_MAP.lock().unwrap()[&arg0].clone();
             if pipe_out || with_output {
-                let handle = thread::Builder::new().spawn(move || internal_cmd(&mut env))?;
+                let subst_fds = std::mem::take(&mut self.subst_fds);
+                let handle = thread::Builder::new()
+                    .spawn(move || {
+                        let ret = internal_cmd.run(&mut env);
+                        drop(subst_fds);
+                        ret
+                    })
+                    .map_err(|e| info.io_error(e, true))?;
                 Ok(CmdChild::new(
-                    CmdChildHandle::Thread(handle),
-                    cmd_str,
+                    CmdChildHandle::Thread(handle, cancelled),
+                    info,
                     self.stdout_logging,
                     self.stderr_logging,
+                    self.stderr_policy,
                 ))
             } else {
-                let child = internal_cmd(&mut env)?;
+                internal_cmd.run(&mut env).map_err(|e| info.fn_error(e))?;
                 Ok(CmdChild::new(
-                    CmdChildHandle::SyncFn(child),
-                    cmd_str,
+                    CmdChildHandle::SyncFn(()),
+                    info,
                     self.stdout_logging,
                     self.stderr_logging,
+                    self.stderr_policy,
                 ))
             }
         } else {
@@ -437,6 +1473,12 @@ impl Cmd {
                 cmd.current_dir(current_dir.clone());
             }
 
+            // setup environment variables, the ones for this command take precedence
+            env.apply(&mut cmd);
+            for (k, v) in self.vars.iter() {
+                cmd.env(k, v);
+            }
+
             // update stdin
             if let Some(redirect_in) = self.stdin_redirect.take() {
                 cmd.stdin(redirect_in);
@@ -452,17 +1494,122 @@ impl Cmd {
                 cmd.stderr(redirect_err);
             }
 
+            // keep the process substitution pipes open in the child process
+            let fds: Vec<RawFd> = self.subst_fds.iter().map(|fd| fd.as_raw_fd()).collect();
+            if !fds.is_empty() {
+                unsafe {
+                    cmd.pre_exec(move || {
+                        for &fd in fds.iter() {
+                            if libc::fcntl(fd, libc::F_SETFD, 0) == -1 {
+                                return Err(Error::last_os_error());
+                            }
+                        }
+                        Ok(())
+                    });
+                }
+            }
+
+            // set up other fds after stdio, with the sources moved above all the targets
+            let mut src_files = vec![];
+            if let Some(&max_fd) = self.fd_redirects.keys().next_back() {
+                let mut fds = vec![];
+                for (&fd, file) in self.fd_redirects.iter() {
+                    let src_fd = match file {
+                        Some(file) => {
+                            let src = Self::dup_fd_above(file.as_raw_fd(), max_fd)
+                                .map_err(|e| info.io_error(e, true))?;
+                            let src_fd = src.as_raw_fd();
+                            src_files.push(src);
+                            src_fd
+                        }
+                        None => -1,
+                    };
+                    fds.push((fd, src_fd));
+                }
+                unsafe {
+                    cmd.pre_exec(move || {
+                        for &(fd, src_fd) in fds.iter() {
+                            if src_fd == -1 {
+                                libc::close(fd);
+                            } else if libc::dup2(src_fd, fd) == -1 {
+                                return Err(Error::last_os_error());
+                            }
+                        }
+                        Ok(())
+                    });
+                }
+            }
+
             // spawning process
-            let child = cmd.spawn()?;
+            let child = cmd.spawn().map_err(|e| info.io_error(e, true))?;
             Ok(CmdChild::new(
-                CmdChildHandle::Proc(child),
-                self.cmd_str(),
+                CmdChildHandle::Proc(child, Arc::new(Mutex::new(false))),
+                info,
                 self.stdout_logging,
                 self.stderr_logging,
+                self.stderr_policy,
             ))
         }
     }
 
+    // builtin or custom commands and subshells only have the stdio, without other fds
+    fn check_fd_redirects(&self) -> CmdResult {
+        let unsupported = self.redirects.iter().find(|redirect| {
+            matches!(
+                redirect,
+                Redirect::FdToFile(fd, ..)
+                    | Redirect::FileToFd(fd, ..)
+                    | Redirect::FdToFd(fd, _)
+                    | Redirect::CloseFd(fd) if *fd > 2
+            )
+        });
+        match unsupported {
+            Some(redirect) => {
+                let err_msg = format!(
+                    "{}: redirection {:?} is not supported by builtin or custom commands, or \
+                     subshells",
+                    self.cmd_str(),
+                    redirect
+                );
+                Err(Error::new(ErrorKind::InvalidInput, err_msg))
+            }
+            None => Ok(()),
+        }
+    }
+
+    fn take_stdin(&mut self) -> Result<CmdIn> {
+        match self.stdin_redirect.take() {
+            Some(redirect_in) => Ok(redirect_in),
+            None => Ok(CmdIn::Pipe(os_pipe::dup_stdin()?)),
+        }
+    }
+
+    fn take_stdout(&mut self) -> Result<CmdOut> {
+        match self.stdout_redirect.take() {
+            Some(redirect_out) => Ok(redirect_out),
+            None => Ok(CmdOut::Pipe(os_pipe::dup_stdout()?)),
+        }
+    }
+
+    fn take_stderr(&mut self) -> Result<CmdOut> {
+        match self.stderr_redirect.take() {
+            Some(redirect_err) => Ok(redirect_err),
+            None => Ok(CmdOut::Pipe(os_pipe::dup_stderr()?)),
+        }
+    }
+
+    // commands in a subshell default to its stdio, before setting up their own redirects
+    fn inherit_subshell_io(&mut self, io: &SubshellIo, first: bool, last: bool) -> CmdResult {
+        if first {
+            self.stdin_redirect = Some(io.stdin.try_clone()?);
+        }
+        if last {
+            self.stdout_redirect = Some(io.stdout.try_clone()?);
+        }
+        self.stderr_redirect = Some(io.stderr.try_clone()?);
+        Ok(())
+    }
+
     fn run_cd_cmd(&self, current_dir: &mut PathBuf) -> CmdResult {
         if self.args.len() == 1 {
             return Err(Error::new(ErrorKind::Other, "cd: missing directory"));
@@ -500,6 +1647,7 @@ impl Cmd {
         pipe_in: &mut Option<PipeReader>,
         pipe_out: Option<PipeWriter>,
         with_output: bool,
+        stderr_policy: &StderrPolicy,
     ) -> CmdResult {
         // set up stdin pipe
         if let Some(pipe) = pipe_in.take() {
@@ -514,11 +1662,19 @@ impl Cmd {
             self.stdout_logging = Some(pipe_reader);
         }
         // set up stderr pipe
-        let (pipe_reader, pipe_writer) = os_pipe::pipe()?;
-        self.stderr_redirect = Some(CmdOut::Pipe(pipe_writer));
-        self.stderr_logging = Some(pipe_reader);
+        match stderr_policy {
+            StderrPolicy::Inherit => {}
+            StderrPolicy::Discard => self.stderr_redirect = Some(CmdOut::Null),
+            _ => {
+                let (pipe_reader, pipe_writer) = os_pipe::pipe()?;
+                self.stderr_redirect = Some(CmdOut::Pipe(pipe_writer));
+                self.stderr_logging = Some(pipe_reader);
+            }
+        }
+        self.stderr_policy = stderr_policy.clone();
 
-        for redirect in self.redirects.iter() {
+        let redirects = std::mem::take(&mut self.redirects);
+        for redirect in redirects.iter() {
             match redirect {
                 Redirect::FileToStdin(path) => {
                     self.stdin_redirect = Some(if path == Path::new("/dev/null") {
@@ -527,6 +1683,16 @@ impl Cmd {
                         CmdIn::File(Self::open_file(path, true, false)?)
                     });
                 }
+                Redirect::DataToStdin(data) => {
+                    // write on a thread, so the command would not be blocked by large inputs
+                    let (pipe_reader, mut pipe_writer) = os_pipe::pipe()?;
+                    let data = data.clone();
+                    thread::spawn(move || {
+                        // the command might exit without reading all the input
+                        let _ = pipe_writer.write_all(&data);
+                    });
+                    self.stdin_redirect = Some(CmdIn::Pipe(pipe_reader));
+                }
                 Redirect::StdoutToStderr => {
                     if let Some(ref redirect) = self.stderr_redirect {
                         self.stdout_redirect = Some(redirect.try_clone()?);
@@ -555,10 +1721,89 @@ impl Cmd {
                         CmdOut::File(Self::open_file(path, false, *append)?)
                     });
                 }
+                Redirect::FdToFile(fd, path, append) => {
+                    let file = Self::open_file(path, false, *append)?;
+                    self.set_fd(*fd, file);
+                }
+                Redirect::FileToFd(fd, path, rw) => {
+                    let file = if *rw {
+                        OpenOptions::new()
+                            .read(true)
+                            .write(true)
+                            .create(true)
+                            .truncate(false)
+                            .open(path)?
+                    } else {
+                        Self::open_file(path, true, false)?
+                    };
+                    self.set_fd(*fd, file);
+                }
+                Redirect::FdToFd(fd1, fd2) => {
+                    let file = self.dup_fd(*fd2)?;
+                    self.set_fd(*fd1, file);
+                }
+                Redirect::CloseFd(fd) => {
+                    match fd {
+                        0 => self.stdin_redirect = Some(CmdIn::Null),
+                        1 => self.stdout_redirect = Some(CmdOut::Null),
+                        2 => self.stderr_redirect = Some(CmdOut::Null),
+                        _ => {}
+                    }
+                    self.fd_redirects.insert(*fd, None);
+                }
             }
         }
+        self.redirects = redirects;
         Ok(())
     }
+
+    fn set_fd(&mut self, fd: i32, file: File) {
+        match fd {
+            0 => self.stdin_redirect = Some(CmdIn::File(file)),
+            1 => self.stdout_redirect = Some(CmdOut::File(file)),
+            2 => self.stderr_redirect = Some(CmdOut::File(file)),
+            _ => {
+                self.fd_redirects.insert(fd, Some(file));
+            }
+        }
+    }
+
+    // duplicate the fd as it is set up so far for this command
+    fn dup_fd(&self, fd: i32) -> Result<File> {
+        let raw_fd = match fd {
+            0 => match self.stdin_redirect {
+                Some(CmdIn::File(ref file)) => file.as_raw_fd(),
+                Some(CmdIn::Pipe(ref pipe)) => pipe.as_raw_fd(),
+                Some(CmdIn::Null) => return Self::open_file(Path::new("/dev/null"), true, false),
+                None => fd,
+            },
+            1 | 2 => match if fd == 1 {
+                &self.stdout_redirect
+            } else {
+                &self.stderr_redirect
+            } {
+                Some(CmdOut::File(file)) => file.as_raw_fd(),
+                Some(CmdOut::Pipe(pipe)) => pipe.as_raw_fd(),
+                Some(CmdOut::Null) => return Self::open_file(Path::new("/dev/null"), false, false),
+                None => fd,
+            },
+            _ => match self.fd_redirects.get(&fd) {
+                Some(Some(file)) => file.as_raw_fd(),
+                Some(None) => return Err(Error::from_raw_os_error(libc::EBADF)),
+                None => fd,
+            },
+        };
+        Self::dup_fd_above(raw_fd, 0)
+    }
+
+    fn dup_fd_above(fd: RawFd, min_fd: RawFd) -> Result<File> {
+        let new_fd = unsafe { libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, min_fd + 1) };
+        if new_fd == -1 {
+            return Err(Error::last_os_error());
+        }
+        // safe, since the new fd is owned by nobody else
+        Ok(unsafe { File::from_raw_fd(new_fd) })
+    }
 }
 
 #[doc(hidden)]
@@ -572,6 +1817,35 @@ impl<T: ToString> AsOsStr for T {
     }
 }
 
+/// Variable fed to stdin by `<<< $var` or `<< $var`
+///
+/// The bytes are used for `AsRef<[u8]>` types, and the other types are converted as arguments,
+/// which is picked by the method resolution of `(&StdinVar(&var)).stdin_data()`.
+#[doc(hidden)]
+pub struct StdinVar<'a, T: ?Sized>(pub &'a T);
+
+#[doc(hidden)]
+pub trait StdinBytes {
+    fn stdin_data(&self) -> Vec<u8>;
+}
+
+impl<T: ?Sized + AsRef<[u8]>> StdinBytes for StdinVar<'_, T> {
+    fn stdin_data(&self) -> Vec<u8> {
+        self.0.as_ref().to_vec()
+    }
+}
+
+#[doc(hidden)]
+pub trait StdinArg {
+    fn stdin_data(&self) -> Vec<u8>;
+}
+
+impl<T: ?Sized + AsOsStr> StdinArg for &StdinVar<'_, T> {
+    fn stdin_data(&self) -> Vec<u8> {
+        self.0.as_os_str().into_vec()
+    }
+}
+
 #[doc(hidden)]
 #[derive(Default)]
 pub struct CmdString(OsString);
@@ -581,6 +1855,22 @@ impl CmdString {
         self
     }
 
+    // unset environment variables are expanded to empty strings, as in bash
+    pub fn append_env(self, name: &str) -> Self {
+        match std::env::var_os(name) {
+            Some(value) => self.append(value),
+            None => self,
+        }
+    }
+
+    // "~user" is kept as it is if the user does not exist, as in bash
+    pub fn append_home(self, user: &str) -> Self {
+        match home_dir(user) {
+            Some(dir) => self.append(dir),
+            None => self.append("~").append(user),
+        }
+    }
+
     pub fn into_os_string(self) -> OsString {
         self.0
     }
@@ -588,6 +1878,10 @@ impl CmdString {
     pub fn into_path_buf(self) -> PathBuf {
         self.0.into()
     }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.0.into_vec()
+    }
 }
 
 impl AsRef<OsStr> for CmdString {
@@ -608,6 +1902,205 @@ impl fmt::Display for CmdString {
     }
 }
 
+// home directory of the user, or of the current user if empty
+fn home_dir(user: &str) -> Option<OsString> {
+    if user.is_empty() {
+        if let Some(home) = std::env::var_os("HOME") {
+            return Some(home);
+        }
+    }
+
+    let mut passwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut result: *mut libc::passwd = std::ptr::null_mut();
+    let mut buf = vec![0 as libc::c_char; 16384];
+    let ret = if user.is_empty() {
+        unsafe {
+            libc::getpwuid_r(
+                libc::geteuid(),
+                &mut passwd,
+                buf.as_mut_ptr(),
+                buf.len(),
+                &mut result,
+            )
+        }
+    } else {
+        let name = std::ffi::CString::new(user).ok()?;
+        unsafe {
+            libc::getpwnam_r(
+                name.as_ptr(),
+                &mut passwd,
+                buf.as_mut_ptr(),
+                buf.len(),
+                &mut result,
+            )
+        }
+    };
+    if ret != 0 || result.is_null() || passwd.pw_dir.is_null() {
+        return None;
+    }
+    let dir = unsafe { std::ffi::CStr::from_ptr(passwd.pw_dir) };
+    Some(OsStr::from_bytes(dir.to_bytes()).to_os_string())
+}
+
+/// Argument with command substitutions or glob patterns, which are resolved when the command is
+/// spawned
+#[doc(hidden)]
+#[derive(Default)]
+pub struct SubstArg(Vec<SubstPart>);
+
+enum SubstPart {
+    Str(OsString),
+    Cmds(GroupCmds<'static>),
+    Pattern(String),
+    Env(String),
+}
+
+impl SubstArg {
+    pub fn append<T: AsRef<OsStr>>(mut self, value: T) -> Self {
+        self.0.push(SubstPart::Str(value.as_ref().to_os_string()));
+        self
+    }
+
+    pub fn append_cmds(mut self, cmds: GroupCmds<'static>) -> Self {
+        self.0.push(SubstPart::Cmds(cmds));
+        self
+    }
+
+    pub fn append_subst(mut self, arg: SubstArg) -> Self {
+        self.0.extend(arg.0);
+        self
+    }
+
+    pub fn append_pattern(mut self, pattern: &str) -> Self {
+        self.0.push(SubstPart::Pattern(pattern.to_string()));
+        self
+    }
+
+    // "${env.NAME}" is read when the command is spawned, so the earlier "export" is visible
+    pub fn append_env(mut self, name: &str) -> Self {
+        self.0.push(SubstPart::Env(name.to_string()));
+        self
+    }
+
+    // the output of commands is trimmed of trailing newlines, as in bash
+    fn resolve(
+        self,
+        current_dir: &Path,
+        env: &GroupEnv,
+        inherited: &Inherited,
+        split: bool,
+    ) -> Result<Vec<OsString>> {
+        let mut ret = OsString::new();
+        let mut pattern = String::new();
+        let mut has_pattern = false;
+        for part in self.0 {
+            let s = match part {
+                SubstPart::Str(s) => s,
+                SubstPart::Cmds(mut cmds) => {
+                    cmds.current_dir = current_dir.to_path_buf();
+                    cmds.env = env.clone();
+                    cmds.inherit(inherited);
+                    let mut output = cmds.run_fun_bytes()?;
+                    while output.last() == Some(&b'\n') {
+                        output.pop();
+                    }
+                    OsString::from_vec(output)
+                }
+                // unset environment variables are expanded to empty strings, as in bash
+                SubstPart::Env(name) => env.var(&name).unwrap_or_default(),
+                SubstPart::Pattern(p) => {
+                    ret.push(&p);
+                    pattern.push_str(&p);
+                    has_pattern = true;
+                    continue;
+                }
+            };
+            pattern.push_str(&glob::Pattern::escape(&s.to_string_lossy()));
+            ret.push(s);
+        }
+
+        if split {
+            return Ok(ret
+                .as_bytes()
+                .split(|c| c.is_ascii_whitespace())
+                .filter(|word| !word.is_empty())
+                .map(|word| OsStr::from_bytes(word).to_os_string())
+                .collect());
+        }
+        let policy = inherited.glob_policy.unwrap_or_else(glob_policy);
+        if !has_pattern || policy == GlobPolicy::Disabled {
+            return Ok(vec![ret]);
+        }
+        let paths = Self::expand_glob(&pattern, current_dir)?;
+        if !paths.is_empty() {
+            return Ok(paths);
+        }
+        match policy {
+            GlobPolicy::Strict => Err(Error::new(
+                ErrorKind::NotFound,
+                format!("no matches found: {}", ret.to_string_lossy()),
+            )),
+            GlobPolicy::Null => Ok(vec![]),
+            _ => Ok(vec![ret]),
+        }
+    }
+
+    // relative patterns are matched in the current directory of the group
+    fn expand_glob(pattern: &str, current_dir: &Path) -> Result<Vec<OsString>> {
+        let prefix = if Path::new(pattern).is_relative() && !current_dir.as_os_str().is_empty() {
+            Some(current_dir)
+        } else {
+            None
+        };
+        let full_pattern = match prefix {
+            Some(dir) => format!(
+                "{}/{}",
+                glob::Pattern::escape(&dir.to_string_lossy()),
+                pattern
+            ),
+            None => pattern.to_string(),
+        };
+        let options = glob::MatchOptions {
+            case_sensitive: true,
+            require_literal_separator: true,
+            require_literal_leading_dot: true,
+        };
+        let paths = glob::glob_with(&full_pattern, options)
+            .map_err(|e| Error::new(ErrorKind::InvalidInput, format!("{}: {}", pattern, e)))?;
+        Ok(paths
+            .filter_map(|path| path.ok())
+            .map(|path| match prefix {
+                Some(dir) => path
+                    .strip_prefix(dir)
+                    .map(|p| p.to_path_buf())
+                    .unwrap_or(path),
+                None => path,
+            })
+            .map(PathBuf::into_os_string)
+            .collect())
+    }
+}
+
+impl From<CmdString> for SubstArg {
+    fn from(s: CmdString) -> Self {
+        Self::default().append(s)
+    }
+}
+
+impl fmt::Display for SubstArg {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for part in self.0.iter() {
+            match part {
+                SubstPart::Str(s) => f.write_str(&s.to_string_lossy())?,
+                SubstPart::Pattern(p) => f.write_str(p)?,
+                SubstPart::Cmds(cmds) => write!(f, "$({})", cmds.full_cmds())?,
+                SubstPart::Env(name) => write!(f, "${{env.{}}}", name)?,
+            }
+        }
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -615,20 +2108,22 @@ mod tests {
     #[test]
     fn test_run_piped_cmds() {
         let mut current_dir = PathBuf::new();
+        let env = GroupEnv::default();
         assert!(Cmds::default()
             .pipe(Cmd::default().add_args(vec!["echo", "rust"]))
             .pipe(Cmd::default().add_args(vec!["wc"]))
-            .run_cmd(&mut current_dir)
+            .run_cmd(&mut current_dir, &env)
             .is_ok());
     }
 
     #[test]
     fn test_run_piped_funs() {
         let mut current_dir = PathBuf::new();
+        let env = GroupEnv::default();
         assert_eq!(
             Cmds::default()
                 .pipe(Cmd::default().add_args(vec!["echo", "rust"]))
-                .run_fun(&mut current_dir)
+                .run_fun(&mut current_dir, &env)
                 .unwrap(),
             "rust"
         );
@@ -637,7 +2132,7 @@ mod tests {
             Cmds::default()
                 .pipe(Cmd::default().add_args(vec!["echo", "rust"]))
                 .pipe(Cmd::default().add_args(vec!["wc", "-c"]))
-                .run_fun(&mut current_dir)
+                .run_fun(&mut current_dir, &env)
                 .unwrap()
                 .trim(),
             "5"
@@ -647,19 +2142,20 @@ mod tests {
     #[test]
     fn test_stdout_redirect() {
         let mut current_dir = PathBuf::new();
+        let env = GroupEnv::default();
         let tmp_file = "/tmp/file_echo_rust";
         let mut write_cmd = Cmd::default().add_args(vec!["echo", "rust"]);
         write_cmd = write_cmd.add_redirect(Redirect::StdoutToFile(PathBuf::from(tmp_file), false));
         assert!(Cmds::default()
             .pipe(write_cmd)
-            .run_cmd(&mut current_dir)
+            .run_cmd(&mut current_dir, &env)
             .is_ok());
 
         let read_cmd = Cmd::default().add_args(vec!["cat", tmp_file]);
         assert_eq!(
             Cmds::default()
                 .pipe(read_cmd)
-                .run_fun(&mut current_dir)
+                .run_fun(&mut current_dir, &env)
                 .unwrap(),
             "rust"
         );
@@ -667,7 +2163,7 @@ mod tests {
         let cleanup_cmd = Cmd::default().add_args(vec!["rm", tmp_file]);
         assert!(Cmds::default()
             .pipe(cleanup_cmd)
-            .run_cmd(&mut current_dir)
+            .run_cmd(&mut current_dir, &env)
             .is_ok());
     }
 }
@@ -253,3 +253,859 @@ fn test_path_as_var() {
     let dir2 = std::path::PathBuf::from("/");
     assert_eq!("/", run_fun!(cd $dir2; pwd).unwrap());
 }
+
+#[test]
+fn test_cmd_error() {
+    let err = run_cmd!(echo "nothing" | grep rust).unwrap_err();
+    let cmd_err = CmdError::from_io_error(&err).unwrap();
+    assert_eq!(cmd_err.args(), ["grep", "rust"]);
+    assert_eq!(cmd_err.position(), 1);
+    assert_eq!(cmd_err.code(), Some(1));
+    assert_eq!(cmd_err.signal(), None);
+
+    let err = run_cmd!(cd /tmp; ls /nofile).unwrap_err();
+    let cmd_err = CmdError::from_io_error(&err).unwrap();
+    assert_eq!(cmd_err.current_dir(), std::path::Path::new("/tmp"));
+    assert!(cmd_err.stderr()[0].contains("/nofile"));
+
+    let err = run_fun!(/bad_cmd_path).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+    assert_eq!(
+        CmdError::from_io_error(&err).unwrap().args(),
+        ["/bad_cmd_path"]
+    );
+}
+
+#[test]
+fn test_pipe_statuses() {
+    let statuses = run_statuses!(echo "rust" | grep -q go | head -1).unwrap();
+    let codes: Vec<_> = statuses.iter().map(|s| s.code()).collect();
+    assert_eq!(codes, [Some(0), Some(1), Some(0)]);
+
+    let statuses = spawn!(false | true).unwrap().wait_statuses().unwrap();
+    assert!(!statuses[0].success());
+    assert!(statuses[1].success());
+
+    let statuses = spawn_with_output!(echo xx | wc -c)
+        .unwrap()
+        .wait_statuses()
+        .unwrap();
+    assert!(statuses.iter().all(|s| s.success()));
+
+    // the output larger than a pipe buffer is drained
+    let statuses = spawn_with_output!(seq 1 200000 | cat)
+        .unwrap()
+        .wait_statuses()
+        .unwrap();
+    assert_eq!(statuses.len(), 2);
+    assert!(statuses.iter().all(|s| s.success()));
+    let statuses = run_statuses!(seq 1 200000 | false | cat).unwrap();
+    assert_eq!(statuses[1].code(), Some(1));
+
+    assert!(run_statuses!(false; true).is_err());
+    assert!(run_statuses!(true | /bad_cmd_path).is_err());
+}
+
+#[test]
+fn test_timeout() {
+    use std::io::ErrorKind;
+    use std::time::{Duration, Instant};
+    let timeout = Duration::from_millis(200);
+
+    let now = Instant::now();
+    let err = run_cmd!(#[timeout(timeout)] sleep 10 | cat).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::TimedOut);
+    assert!(now.elapsed() < Duration::from_secs(5));
+    assert!(run_cmd!(#[timeout(Duration::from_secs(10))] sleep 0.1; echo done).is_ok());
+    assert_eq!(
+        run_fun!(#[timeout(timeout)] echo xx; sleep 10)
+            .unwrap_err()
+            .kind(),
+        ErrorKind::TimedOut
+    );
+
+    // SIGTERM is ignored, escalating to SIGKILL
+    let now = Instant::now();
+    let err = spawn!(#[kill_grace(timeout)] bash -c "trap '' TERM; sleep 10")
+        .unwrap()
+        .wait_timeout(timeout)
+        .unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::TimedOut);
+    assert_eq!(CmdError::from_io_error(&err).unwrap().signal(), Some(9));
+    assert!(now.elapsed() < Duration::from_secs(5));
+
+    let mut proc = spawn_with_output!(echo xx).unwrap();
+    assert_eq!(proc.wait_timeout(Duration::from_secs(10)).unwrap(), "xx");
+
+    // the command substitutions are killed as well
+    let now = Instant::now();
+    let err = run_cmd!(#[timeout(timeout)] echo $(sleep 10)).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::TimedOut);
+    assert!(now.elapsed() < Duration::from_secs(5));
+
+    // the commands in a subshell are killed as well
+    let now = Instant::now();
+    let err = run_cmd!(#[timeout(timeout)] (sleep 10; echo done)).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::TimedOut);
+    let err = spawn!((sleep 10))
+        .unwrap()
+        .wait_timeout(timeout)
+        .unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::TimedOut);
+    assert!(now.elapsed() < Duration::from_secs(5));
+}
+
+#[test]
+fn test_kill_and_signal() {
+    use std::time::{Duration, Instant};
+
+    let mut proc = spawn!(sleep 10 | cat).unwrap();
+    assert_eq!(proc.pids().len(), 2);
+    assert!(!proc.try_wait().unwrap());
+    proc.kill().unwrap();
+    let err = proc.wait().unwrap_err();
+    assert_eq!(CmdError::from_io_error(&err).unwrap().signal(), Some(9));
+
+    let mut proc = spawn!(sleep 10).unwrap();
+    proc.signal(libc::SIGTERM).unwrap();
+    assert!(proc.wait().is_err());
+
+    // the reaped children are not signaled any more, since their pids could be reused
+    let mut proc = spawn!(true).unwrap();
+    while !proc.try_wait().unwrap() {
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    proc.signal(libc::SIGTERM).unwrap();
+    assert!(proc.wait().is_ok());
+
+    let now = Instant::now();
+    let mut proc = spawn!((sleep 10; echo done)).unwrap();
+    proc.kill().unwrap();
+    assert!(proc.wait().is_err());
+    assert!(now.elapsed() < Duration::from_secs(5));
+
+    let mut proc = spawn!(true).unwrap();
+    let now = Instant::now();
+    while !proc.try_wait().unwrap() {
+        assert!(now.elapsed() < Duration::from_secs(5));
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    assert!(proc.kill().is_ok());
+    assert!(proc.wait().is_ok());
+
+    // custom commands are cancelled cooperatively
+    #[export_cmd(my_loop)]
+    fn my_loop(env: &mut CmdEnv) -> CmdResult {
+        while !env.is_cancelled() {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Interrupted,
+            "cancelled",
+        ))
+    }
+    use_custom_cmd!(my_loop);
+    let mut proc = spawn_with_output!(my_loop | cat).unwrap();
+    assert_eq!(proc.pids().len(), 1);
+    proc.kill().unwrap();
+    assert!(proc.wait_with_output().is_err());
+}
+
+#[test]
+fn test_run_fun_full() {
+    let output = run_fun_full!(echo xx | cat; echo yy >&2).unwrap();
+    assert!(output.status.success());
+    assert!(output.stdout.is_empty());
+    assert_eq!(output.stderr, b"yy\n");
+
+    let output = run_fun_full!(ls "/nofile").unwrap();
+    assert_eq!(output.status.code(), Some(2));
+    assert!(output.stdout.is_empty());
+    assert!(!output.stderr.is_empty());
+
+    let output = run_fun_full!(bash -c "echo a >&2; exit 3" | bash -c "cat; echo b >&2").unwrap();
+    assert_eq!(output.status.code(), Some(3));
+    assert_eq!(output.stderr, b"a\nb\n");
+
+    let mut proc = spawn_with_output!(bash -c "echo out; echo err >&2").unwrap();
+    let output = proc.wait_with_full_output().unwrap();
+    assert_eq!(output.stdout, b"out\n");
+    assert_eq!(output.stderr, b"err\n");
+
+    assert!(run_fun_full!(/bad_cmd_path).is_err());
+}
+
+#[test]
+fn test_stderr_policy() {
+    use std::sync::{Arc, Mutex};
+
+    let buf = Arc::new(Mutex::new(vec![]));
+    run_cmd!(#[stderr(StderrPolicy::Capture(buf.clone()))] echo xx >&2; bash -c "echo yy >&2")
+        .unwrap();
+    let output = buf.lock().unwrap().clone();
+    assert_eq!(output, b"xx\nyy\n");
+
+    // explicit redirections take precedence
+    let buf = Arc::new(Mutex::new(vec![]));
+    run_cmd!(#[stderr(StderrPolicy::Capture(buf.clone()))] echo zz 2>/dev/null >&2).unwrap();
+    assert!(buf.lock().unwrap().is_empty());
+
+    let lines = Arc::new(Mutex::new(vec![]));
+    let lines_clone = lines.clone();
+    let policy = StderrPolicy::callback(move |cmd, line| {
+        lines_clone
+            .lock()
+            .unwrap()
+            .push(format!("{}: {}", cmd, String::from_utf8_lossy(line)))
+    });
+    let err = run_cmd!(#[stderr(policy)] ls "/nofile").unwrap_err();
+    let lines = lines.lock().unwrap();
+    assert_eq!(lines.len(), 1);
+    assert!(lines[0].starts_with("ls: "));
+    assert_eq!(CmdError::from_io_error(&err).unwrap().stderr().len(), 1);
+
+    assert!(run_cmd!(#[stderr(StderrPolicy::Discard)] ls "/nofile").is_err());
+    assert!(run_cmd!(#[stderr(StderrPolicy::Inherit)] echo "inherited" >&2).is_ok());
+
+    // command substitutions follow the same policy
+    let buf = Arc::new(Mutex::new(vec![]));
+    run_cmd!(#[stderr(StderrPolicy::Capture(buf.clone()))] echo $(bash -c "echo ww >&2")).unwrap();
+    assert_eq!(*buf.lock().unwrap(), b"ww\n");
+    let output = run_fun_full!(echo $(bash -c "echo ww >&2")).unwrap();
+    assert_eq!(output.stderr, b"ww\n");
+}
+
+#[test]
+fn test_raw_output() {
+    use std::os::unix::ffi::OsStrExt;
+
+    let data = run_fun_bytes!(printf "\\x00\\xff\\n\\n").unwrap();
+    assert_eq!(data, b"\x00\xff\n\n");
+    assert_eq!(run_fun_bytes!(true).unwrap(), b"");
+    assert!(run_fun_bytes!(false | cat).is_err());
+    assert_eq!(run_fun_bytes!(ignore false).unwrap(), b"");
+
+    let mut proc = spawn_with_output!(printf "\\xfe/path\\n").unwrap();
+    let path = proc.wait_with_os_output().unwrap();
+    assert_eq!(path.as_bytes(), b"\xfe/path");
+
+    let mut proc = spawn_with_output!(echo xx | gzip -c | gzip -dc).unwrap();
+    assert_eq!(proc.wait_with_raw_output().unwrap(), b"xx\n");
+}
+
+#[test]
+fn test_lines() {
+    use std::time::{Duration, Instant};
+
+    let lines: Vec<String> = spawn_with_output!(seq 1 5 | grep -v 3)
+        .unwrap()
+        .lines()
+        .map(|line| line.unwrap())
+        .collect();
+    assert_eq!(lines, ["1", "2", "4", "5"]);
+
+    // the error comes as the last item
+    let mut lines = spawn_with_output!(bash -c "echo xx; exit 1" | cat)
+        .unwrap()
+        .lines();
+    assert_eq!(lines.next().unwrap().unwrap(), "xx");
+    assert!(lines.next().unwrap().is_err());
+    assert!(lines.next().is_none());
+
+    // dropping early kills the running commands
+    let now = Instant::now();
+    let lines: Vec<String> =
+        spawn_with_output!(bash -c "while true; do echo yy; sleep 0.01; done" | cat)
+            .unwrap()
+            .lines()
+            .take(3)
+            .map(|line| line.unwrap())
+            .collect();
+    assert_eq!(lines, ["yy", "yy", "yy"]);
+    assert!(now.elapsed() < Duration::from_secs(5));
+
+    // stderr is drained while streaming, and its tail is still in the error
+    let lines: Vec<String> = spawn_with_output!(
+        bash -c "head -c 200000 /dev/zero | tr '\\0' x >&2; echo done"
+    )
+    .unwrap()
+    .lines()
+    .map(|line| line.unwrap())
+    .collect();
+    assert_eq!(lines, ["done"]);
+    let err = spawn_with_output!(bash -c "echo oops >&2; exit 1")
+        .unwrap()
+        .lines()
+        .last()
+        .unwrap()
+        .unwrap_err();
+    assert_eq!(CmdError::from_io_error(&err).unwrap().stderr(), ["oops"]);
+}
+
+#[test]
+#[rustfmt::skip]
+fn test_piped_stdin() {
+    use std::io::Write;
+
+    let mut proc = spawn_with_output!(#[piped_stdin] cat | wc -l).unwrap();
+    let mut stdin = proc.take_stdin().unwrap();
+    std::thread::spawn(move || {
+        for i in 0..1000 {
+            writeln!(stdin, "line {}", i).unwrap();
+        }
+    });
+    assert_eq!(proc.wait_with_output().unwrap().trim(), "1000");
+
+    // stdin is closed before waiting, if not taken
+    let mut proc = spawn!(#[piped_stdin] cat).unwrap();
+    assert!(proc.wait().is_ok());
+
+    // not piped by default
+    assert!(spawn!(true).unwrap().take_stdin().is_none());
+}
+
+#[test]
+#[rustfmt::skip]
+fn test_redirect_data_to_stdin() {
+    let name = "rust";
+    assert_eq!(run_fun!(cat <<< "hello, $name").unwrap(), "hello, rust");
+    assert_eq!(run_fun!(wc -l <<< $name).unwrap().trim(), "1");
+    assert_eq!(run_fun!(wc -l << $name).unwrap().trim(), "0");
+    assert_eq!(run_fun!(cat << "a\nb" | wc -l).unwrap().trim(), "1");
+
+    let bytes: &[u8] = b"\x00\xff";
+    assert_eq!(run_fun_bytes!(cat << $bytes).unwrap(), bytes);
+    assert_eq!(run_fun_bytes!(cat <<< ${bytes}).unwrap(), b"\x00\xff\n");
+
+    // the same types as arguments are also accepted
+    let n = 42;
+    let path = std::path::Path::new("/tmp").display();
+    assert_eq!(run_fun!(cat <<< $n).unwrap(), "42");
+    assert_eq!(run_fun!(cat << ${path}).unwrap(), "/tmp");
+
+    // large input would not block
+    let data = "x".repeat(1 << 20);
+    assert_eq!(run_fun!(wc -c << $data).unwrap().trim(), "1048576");
+    assert!(run_cmd!(true << $data).is_ok());
+}
+
+#[test]
+#[rustfmt::skip]
+fn test_redirect_to_buffer() {
+    let mut buf = vec![];
+    let mut s = String::from("xx\n");
+    let mut s2 = String::from("old");
+    let dir = run_fun! {
+        cd /tmp;
+        pwd > &mut $buf;
+        echo zz | tr a-z A-Z >> &mut ${s};
+        echo yy > &mut $s2;
+        pwd;
+    }
+    .unwrap();
+    assert_eq!(buf, b"/tmp\n");
+    assert_eq!(s, "xx\nZZ\n");
+    assert_eq!(s2, "yy\n");
+    assert_eq!(dir, "/tmp");
+
+    let mut s = String::from("old");
+    assert!(run_cmd!(ls /nofile > &mut $s 2>&1).is_err());
+    assert_eq!(s, "old");
+    run_cmd!(ignore ls /nofile > &mut $s 2>&1).unwrap();
+    assert!(s.contains("nofile"));
+}
+
+#[test]
+fn test_and_or() {
+    assert_eq!(run_fun!(true && echo yes || echo no).unwrap(), "yes");
+    assert_eq!(run_fun!(false && echo yes || echo no).unwrap(), "no");
+    assert_eq!(run_fun!(false || echo yes).unwrap(), "yes");
+    assert_eq!(run_fun!(true || echo yes).unwrap(), "");
+    assert!(run_fun!(echo xx | grep -q yy && echo found).is_err());
+
+    // only the failure of the last commands in the list is an error, and the last list returns
+    // the result of its last commands which ran, like "$?"
+    let err = run_cmd!(false && echo yes).unwrap_err();
+    assert_eq!(CmdError::from_io_error(&err).unwrap().code(), Some(1));
+    assert!(run_cmd!(false && echo yes; true).is_ok());
+    assert!(run_cmd!((ls /nofile && echo yes)).is_err());
+    assert_eq!(
+        run_fun_full!(sh -c "exit 3" && echo yes)
+            .unwrap()
+            .status
+            .code(),
+        Some(3)
+    );
+    assert!(run_cmd!(true && false).is_err());
+    assert!(run_cmd!(false || false).is_err());
+    assert!(run_cmd!(false || true; false && true; true).is_ok());
+    assert!(run_cmd!(true && false || true).is_ok());
+
+    let mut dir = String::new();
+    run_cmd! {
+        cd /tmp && pwd > &mut $dir;
+        ls /nofile || echo "fallback" >&2;
+    }
+    .unwrap();
+    assert_eq!(dir, "/tmp\n");
+
+    assert_eq!(run_statuses!(false && true).unwrap().len(), 0);
+    assert!(run_fun_full!(false || echo xx).unwrap().status.success());
+}
+
+#[test]
+#[rustfmt::skip]
+fn test_negation() {
+    assert!(run_cmd!(! false).is_ok());
+    assert!(run_cmd!(! ls /nofile).is_ok());
+    let err = run_cmd!(! echo xx | grep -q xx).unwrap_err();
+    assert!(err.to_string().contains("grep"));
+    assert!(run_cmd!(ignore ! true).is_ok());
+    assert_eq!(run_fun!(! grep -q yy <<< "xx" && echo "no yy").unwrap(), "no yy");
+    assert_eq!(run_fun!(! echo xx).unwrap_err().kind(), std::io::ErrorKind::Other);
+
+    // the spawned children and background jobs are negated when waited
+    assert!(spawn!(! false).unwrap().wait().is_ok());
+    assert!(spawn!(! true).unwrap().wait().is_err());
+    assert_eq!(spawn_with_output!(! false).unwrap().wait_with_output().unwrap(), "");
+    assert!(spawn_with_output!(! echo xx).unwrap().wait_with_output().is_err());
+    assert!(spawn_with_output!(! false).unwrap().lines().all(|line| line.is_ok()));
+    assert!(run_cmd!(! false & ; wait).is_ok());
+    assert!(run_cmd!(! true & ; wait).is_err());
+    assert!(run_fun_full!(! false).unwrap().status.success());
+    assert_eq!(run_fun_full!(! echo xx).unwrap().status.code(), Some(1));
+    let err = run_cmd!(#[timeout(std::time::Duration::from_millis(100))] ! sleep 10).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+}
+
+#[test]
+#[rustfmt::skip]
+fn test_proc_subst() {
+    assert!(run_cmd!(diff <(printf "b\na\n" | sort) <(printf "a\nb\n")).is_ok());
+    assert!(run_cmd!(diff <(echo a) <(echo b) > /dev/null).is_err());
+    assert_eq!(run_fun!(cat <(echo xx | tr x y)).unwrap(), "yy");
+    assert_eq!(run_fun!(paste -d, <(seq 1 3) <(seq 4 6) | tail -n 1).unwrap(), "3,6");
+
+    // failure of the inner commands is an error of the outer pipeline
+    let err = run_cmd!(cat <(ls /nofile) > /dev/null).unwrap_err();
+    assert!(err.to_string().contains("ls"));
+
+    let file = "/tmp/_cmd_lib_proc_subst.txt";
+    run_cmd!(echo xx | tee >(cat > $file) > /dev/null).unwrap();
+    assert_eq!(run_fun!(cat $file).unwrap(), "xx");
+    run_cmd!(rm $file).unwrap();
+    assert!(run_fun!(echo <(true)).unwrap().starts_with("/dev/fd/"));
+
+    // the inner commands are not counted as the stages of the pipeline
+    assert_eq!(run_statuses!(cat <(true) | true).unwrap().len(), 2);
+    let mut proc = spawn!(cat <(sleep 0.1) | cat).unwrap();
+    assert_eq!(proc.pids().len(), 2);
+    proc.wait().unwrap();
+}
+
+#[test]
+#[rustfmt::skip]
+fn test_cmd_subst() {
+    assert_eq!(run_fun!(echo $(echo xx | tr x y)).unwrap(), "yy");
+    assert_eq!(run_fun!(echo a$(echo b)c "$(echo x  y)").unwrap(), "abc x y");
+    let name = "rust";
+    assert_eq!(run_fun!(echo "hi, $(echo $name | tr a-z A-Z)!").unwrap(), "hi, RUST!");
+    assert_eq!(run_fun!(echo "[$(printf \"a b\n\n\")]").unwrap(), "[a b]");
+
+    // one argument, unless split with "$[(...)]"
+    assert_eq!(run_fun!(printf "%s\n" $(echo a b) | wc -l).unwrap().trim(), "1");
+    assert_eq!(run_fun!(printf "%s\n" $[(echo a b)] | wc -l).unwrap().trim(), "2");
+    assert_eq!(run_fun!($[(echo echo xx)]).unwrap(), "xx");
+
+    // run when spawning, in the current directory of the group
+    assert_eq!(run_fun!(cd /tmp; echo $(pwd)).unwrap(), "/tmp");
+
+    let err = run_cmd!(echo $(ls /nofile)).unwrap_err();
+    assert!(err.to_string().contains("ls"));
+    assert!(run_cmd!(echo $(false && true)).is_err());
+    assert!(run_cmd!(echo $(false && true; true)).is_ok());
+}
+
+#[test]
+#[rustfmt::skip]
+fn test_redirect_fds() {
+    let file = "/tmp/_cmd_lib_redirect_fds.txt";
+    run_cmd!(sh -c "echo xx >&3" 3> $file).unwrap();
+    run_cmd!(sh -c "echo yy >&3" 3>> $file).unwrap();
+    assert_eq!(run_fun!(sh -c "cat <&4" 4< $file).unwrap(), "xx\nyy");
+    assert_eq!(run_fun!(cat 5< $file <&5 | wc -l).unwrap().trim(), "2");
+
+    // "<>" opens the file for both reading and writing, without truncation
+    run_cmd!(sh -c "echo zz >&6" 6<> $file).unwrap();
+    assert_eq!(run_fun!(cat 0<> $file).unwrap(), "zz\nyy");
+    run_cmd!(rm $file).unwrap();
+
+    assert_eq!(run_fun!(sh -c "echo to3 >&3" 3>&1).unwrap(), "to3");
+    assert_eq!(run_fun!(sh -c "echo out; echo err >&2" 3>&1 1>&2 2>&3).unwrap(), "err");
+    assert_eq!(run_fun!(sh -c "echo xx >&3 || echo closed" 3>&- 2>/dev/null).unwrap(), "closed");
+    assert_eq!(run_fun!(sh -c "echo out; echo err >&2" >&-).unwrap(), "");
+
+    // builtin or custom commands and subshells only have the stdio
+    use std::io::Write;
+    register_cmd("fd_cmd", |env: &mut CmdEnv| writeln!(env.stdout(), "xx"));
+    assert_eq!(run_fun!(fd_cmd 2>&- >&2).unwrap(), "");
+    let err = run_cmd!(fd_cmd 3> $file >&3).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+    assert!(err.to_string().contains("fd_cmd"));
+    assert!(err.to_string().contains("3> /tmp/_cmd_lib_redirect_fds.txt"));
+    assert!(run_cmd!(fd_cmd 3>&-).is_err());
+    assert!(run_cmd!((sh -c "echo xx >&3") 3> $file).is_err());
+    run_cmd!(rm -f $file).unwrap();
+}
+
+#[test]
+fn test_background_jobs() {
+    let file = "/tmp/_cmd_lib_background_jobs.txt";
+    run_cmd! {
+        rm -f $file;
+        sh -c "sleep 0.2; echo job1 >> $file" &
+        sh -c "sleep 0.1; echo job2 >> $file" &
+        echo main >> $file;
+        wait;
+        echo done >> $file;
+    }
+    .unwrap();
+    assert_eq!(run_fun!(cat $file).unwrap(), "main\njob2\njob1\ndone");
+    run_cmd!(rm $file).unwrap();
+
+    // the background jobs are started in parallel
+    let now = std::time::Instant::now();
+    run_cmd!(sleep 0.3 & sleep 0.3 & wait).unwrap();
+    assert!(now.elapsed() < std::time::Duration::from_millis(550));
+
+    // failures are reported by "wait"
+    assert!(run_cmd!(ls /nofile & ; wait).is_err());
+    assert!(run_cmd!(ignore ls /nofile & ; wait).is_ok());
+    assert!(run_cmd!(false & true).is_ok());
+    assert_eq!(run_fun!(true & echo xx).unwrap(), "xx");
+
+    // the jobs without "wait" are waited at the end
+    let now = std::time::Instant::now();
+    run_cmd!(sh -c "sleep 0.3; echo job >> $file" &).unwrap();
+    assert!(now.elapsed() >= std::time::Duration::from_millis(300));
+    assert_eq!(run_fun!(cat $file).unwrap(), "job");
+    run_cmd!(rm $file).unwrap();
+}
+
+#[test]
+#[rustfmt::skip]
+fn test_subshell() {
+    // "cd" only applies inside the subshell
+    let dirs = run_fun! {
+        cd /;
+        (cd /tmp);
+        pwd;
+    }
+    .unwrap();
+    assert_eq!(dirs, "/");
+    assert_eq!(run_fun!((cd /tmp; pwd)).unwrap(), "/tmp");
+
+    // redirecting the output of the whole subshell
+    let file = "/tmp/_cmd_lib_subshell.txt";
+    run_cmd!((echo xx; echo yy) > $file).unwrap();
+    assert_eq!(run_fun!(cat $file).unwrap(), "xx\nyy");
+    run_cmd!((ls /nofile; echo zz >&2) 2> $file || true).unwrap();
+    assert!(run_fun!(cat $file).unwrap().contains("nofile"));
+    run_cmd!(rm $file).unwrap();
+
+    assert_eq!(run_fun!((echo a; echo b) | wc -l).unwrap().trim(), "2");
+    assert_eq!(run_fun!(echo xx | (cat; echo yy)).unwrap(), "xx\nyy");
+    assert!(run_cmd!((true; false)).is_err());
+    assert!(run_cmd!((false) || true).is_ok());
+
+    // the subshell has no arguments of its own, and its commands are shown as they are
+    let err = run_cmd!(echo xx | (echo "a b"; false)).unwrap_err();
+    assert!(err.to_string().contains(r#"(["echo", "a b"]; ["false"])"#));
+    let cmd_err = CmdError::from_io_error(&err).unwrap();
+    assert!(cmd_err.args().is_empty());
+    assert_eq!(cmd_err.position(), 1);
+
+    // the exit status of the subshell is the one of its failed commands
+    use std::os::unix::process::ExitStatusExt;
+    let statuses = run_statuses!((sh -c "exit 3") | (sh -c "kill -9 $$$$")).unwrap();
+    assert_eq!(statuses[0].code(), Some(3));
+    assert_eq!(statuses[1].signal(), Some(9));
+    let err = run_cmd!((true; sh -c "exit 4")).unwrap_err();
+    assert_eq!(CmdError::from_io_error(&err).unwrap().code(), Some(4));
+
+    // "die" fails the command instead of exiting the process
+    use_builtin_cmd!(die);
+    let err = run_cmd!((die "fatal")).unwrap_err();
+    assert_eq!(CmdError::from_io_error(&err).unwrap().code(), Some(1));
+}
+
+#[test]
+#[rustfmt::skip]
+fn test_glob() {
+    let dir = "/tmp/_cmd_lib_glob";
+    run_cmd! {
+        rm -rf $dir;
+        mkdir -p $dir/sub;
+        cd $dir;
+        touch a1.txt a2.txt b1.txt .hidden.txt sub/c1.txt;
+    }
+    .unwrap();
+
+    // disabled by default
+    assert_eq!(run_fun!(echo *.txt).unwrap(), "*.txt");
+
+    // the policy is set for each macro call, without changing the global one for other tests
+    let strict = GlobPolicy::Strict;
+    assert_eq!(run_fun!(#[glob(strict)] cd $dir; echo *.txt).unwrap(), "a1.txt a2.txt b1.txt");
+    assert_eq!(run_fun!(#[glob(strict)] cd $dir; echo a?.txt).unwrap(), "a1.txt a2.txt");
+    assert_eq!(run_fun!(#[glob(strict)] cd $dir; echo [ab]1.txt).unwrap(), "a1.txt b1.txt");
+    assert_eq!(run_fun!(#[glob(strict)] cd $dir; echo [!a]*).unwrap(), "b1.txt sub");
+    assert_eq!(run_fun!(#[glob(strict)] cd $dir; echo **/c*.txt).unwrap(), "sub/c1.txt");
+    // "/*" starts a comment in rust, so it needs to be quoted
+    assert_eq!(run_fun!(#[glob(strict)] echo ${dir}"/sub/"*).unwrap(), format!("{}/sub/c1.txt", dir));
+    // subshells and substitutions use the same policy
+    assert_eq!(run_fun!(#[glob(strict)] cd $dir; (echo b*)).unwrap(), "b1.txt");
+    assert_eq!(run_fun!(#[glob(strict)] cd $dir; echo $(echo b*)).unwrap(), "b1.txt");
+
+    // quoted strings and variables are never expanded
+    let pattern = "*.txt";
+    assert_eq!(run_fun!(#[glob(strict)] cd $dir; echo "*.txt" $pattern).unwrap(), "*.txt *.txt");
+
+    // no matches
+    let err = run_cmd!(#[glob(strict)] cd $dir; echo *.rs).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+    assert_eq!(run_fun!(#[glob(GlobPolicy::PassThrough)] cd $dir; echo *.rs).unwrap(), "*.rs");
+    assert_eq!(run_fun!(#[glob(GlobPolicy::Null)] cd $dir; echo x *.rs).unwrap(), "x");
+
+    run_cmd!(rm -rf $dir).unwrap();
+}
+
+#[test]
+#[rustfmt::skip]
+fn test_env_and_tilde() {
+    let home = std::env::var("HOME").unwrap();
+    assert_eq!(run_fun!(echo ${env.HOME}).unwrap(), home);
+    assert_eq!(run_fun!(echo "${env.HOME}/x" ${env.HOME}/y).unwrap(), format!("{0}/x {0}/y", home));
+    assert_eq!(run_fun!(echo "[${env._CMD_LIB_NO_SUCH_VAR}]").unwrap(), "[]");
+
+    // rust variables are still used for "$var"
+    let env = "rust";
+    assert_eq!(run_fun!(echo ${env} $env).unwrap(), "rust rust");
+
+    assert_eq!(run_fun!(echo ~).unwrap(), home);
+    assert_eq!(run_fun!(echo ~/x).unwrap(), format!("{}/x", home));
+    assert_eq!(run_fun!(echo ~root).unwrap(), run_fun!(bash -c "echo ~root").unwrap());
+    assert_eq!(run_fun!(echo ~_cmd_lib_no_user a~ "~").unwrap(), "~_cmd_lib_no_user a~ ~");
+}
+
+#[test]
+#[rustfmt::skip]
+fn test_export_unset() {
+    let output = run_fun! {
+        export _CMD_LIB_X=1 _CMD_LIB_Y="a b";
+        sh -c "echo $$_CMD_LIB_X $$_CMD_LIB_Y";
+    }
+    .unwrap();
+    assert_eq!(output, "1 a b");
+    assert!(std::env::var("_CMD_LIB_X").is_err());
+
+    // the variables for one command take precedence
+    assert_eq!(run_fun!(export _CMD_LIB_X=1; _CMD_LIB_X=2 printenv _CMD_LIB_X).unwrap(), "2");
+    assert_eq!(run_fun!(export _CMD_LIB_X=$(echo xx); printenv _CMD_LIB_X).unwrap(), "xx");
+
+    // "unset" also hides the variables of the current process
+    let output = run_fun! {
+        unset HOME;
+        sh -c "echo [$$HOME]";
+    }
+    .unwrap();
+    assert_eq!(output, "[]");
+
+    // subshells and substitutions inherit the environment, without changing it
+    let output = run_fun! {
+        export _CMD_LIB_X=1;
+        (export _CMD_LIB_X=2; printenv _CMD_LIB_X);
+        printenv _CMD_LIB_X;
+    }
+    .unwrap();
+    assert_eq!(output, "1");
+    assert_eq!(run_fun!(export _CMD_LIB_X=1; (printenv _CMD_LIB_X)).unwrap(), "1");
+    assert_eq!(run_fun!(export _CMD_LIB_X=1; echo $(printenv _CMD_LIB_X)).unwrap(), "1");
+
+    // "${env.NAME}" is read when each command runs
+    assert_eq!(run_fun!(export _CMD_LIB_X=1; echo "${env._CMD_LIB_X}" ${env._CMD_LIB_X}x).unwrap(), "1 1x");
+    assert_eq!(run_fun!(export _CMD_LIB_X=1; echo "$(echo ${env._CMD_LIB_X})").unwrap(), "1");
+    assert_eq!(run_fun!(unset HOME; echo "[${env.HOME}]").unwrap(), "[]");
+
+    assert!(run_cmd!(export 1X=1).is_err());
+    assert!(run_cmd!(export _CMD_LIB_X).is_err());
+    assert!(run_cmd!(unset _CMD_LIB_X=1).is_err());
+}
+
+#[test]
+#[rustfmt::skip]
+fn test_env_clear() {
+    assert_eq!(run_fun!(#[env_clear] /usr/bin/env).unwrap(), "");
+    assert_eq!(run_fun!(#[env_clear] FOO=1 /usr/bin/env).unwrap(), "FOO=1");
+    assert_eq!(run_fun!(#[env_clear] export FOO=1; /usr/bin/env).unwrap(), "FOO=1");
+    let path = std::env::var("PATH").unwrap();
+    assert_eq!(
+        run_fun!(#[env_keep(["PATH", "_CMD_LIB_NO_SUCH_VAR"])] env | cat).unwrap(),
+        format!("PATH={}", path)
+    );
+    assert_eq!(
+        run_fun!(#[env_remove(["HOME"])] sh -c "echo [$$HOME]").unwrap(),
+        "[]"
+    );
+
+    // also for the builtin commands
+    #[export_cmd(my_var)]
+    fn my_var(env: &mut CmdEnv) -> CmdResult {
+        let value = env.var("FOO").cloned().unwrap_or_default();
+        writeln!(env.stdout(), "[{}]", value)
+    }
+    use std::io::Write;
+    use_custom_cmd!(my_var);
+    assert_eq!(run_fun!(export FOO=1; my_var).unwrap(), "[1]");
+    assert_eq!(run_fun!(export FOO=1; unset FOO; my_var).unwrap(), "[]");
+    assert_eq!(run_fun!(#[env_remove(["FOO"])] FOO=2 my_var).unwrap(), "[2]");
+
+    // only for the commands in one pipeline
+    assert_eq!(run_fun!(export FOO=1; #[env_remove(["FOO"])] my_var | cat).unwrap(), "[]");
+    assert_eq!(run_fun!(export FOO=1; #[env_remove(["FOO"])] true; my_var).unwrap(), "[1]");
+    assert_eq!(run_fun!(export FOO=1; true && #[env_clear] my_var).unwrap(), "[1]");
+    assert_eq!(run_fun!(true; #[env_clear] /usr/bin/env).unwrap(), "");
+
+    // builder api
+    let output = GroupCmds::default()
+        .env_clear()
+        .append(Cmds::default().pipe(Cmd::default().add_args(["/usr/bin/env"])))
+        .run_fun()
+        .unwrap();
+    assert_eq!(output, "");
+    let output = GroupCmds::default()
+        .append(
+            Cmds::default()
+                .env_keep(["PATH"])
+                .pipe(Cmd::default().add_args(["env"])),
+        )
+        .run_fun()
+        .unwrap();
+    assert_eq!(output, format!("PATH={}", path));
+}
+
+#[test]
+fn test_custom_cmd_env() {
+    use std::ffi::OsString;
+    use std::io::Write;
+    use std::os::unix::ffi::{OsStrExt, OsStringExt};
+
+    #[export_cmd(my_env)]
+    fn my_env(env: &mut CmdEnv) -> CmdResult {
+        let home = env.var("HOME").cloned().unwrap_or_default();
+        let count = env.vars_os().count();
+        writeln!(env.stdout(), "[{}] {}", home, count)
+    }
+
+    #[export_cmd(my_args_os)]
+    fn my_args_os(env: &mut CmdEnv) -> CmdResult {
+        let arg = env.args_os()[1].clone();
+        env.stdout().write_all(arg.as_bytes())
+    }
+    use_custom_cmd!(my_env, my_args_os);
+
+    // the same environment as external commands
+    let home = std::env::var("HOME").unwrap();
+    assert!(run_fun!(my_env)
+        .unwrap()
+        .starts_with(&format!("[{}] ", home)));
+    assert_eq!(
+        run_fun!(
+            #[env_clear]
+            my_env
+        )
+        .unwrap(),
+        "[] 0"
+    );
+    assert_eq!(
+        run_fun!(
+            #[env_keep(["HOME"])]
+            my_env
+        )
+        .unwrap(),
+        format!("[{}] 1", home)
+    );
+
+    let args = vec![OsString::from_vec(vec![b'a', 0xff])];
+    assert_eq!(run_fun_bytes!(my_args_os $[args]).unwrap(), b"a\xff");
+}
+
+#[test]
+fn test_custom_cmd_exit() {
+    use std::io::Read;
+
+    #[export_cmd(my_grep)]
+    fn my_grep(env: &mut CmdEnv) -> CmdResult {
+        let mut input = String::new();
+        env.stdin().read_to_string(&mut input)?;
+        let pattern = env.args()[1].clone();
+        let found = input.lines().any(|line| line.contains(&pattern));
+        env.exit(if found { 0 } else { 1 })
+    }
+
+    #[export_cmd(my_exit)]
+    fn my_exit(env: &mut CmdEnv) -> CmdResult {
+        let code = env.args()[1].parse().unwrap();
+        env.exit(code)
+    }
+    use_custom_cmd!(my_grep, my_exit);
+
+    assert!(run_cmd!(echo xx | my_grep xx).is_ok());
+    let err = run_cmd!(echo xx | my_grep yy).unwrap_err();
+    assert_eq!(CmdError::from_io_error(&err).unwrap().code(), Some(1));
+    let err = run_cmd!(my_exit 3).unwrap_err();
+    assert_eq!(CmdError::from_io_error(&err).unwrap().code(), Some(3));
+    assert!(run_cmd!(my_exit 256).is_ok());
+
+    // the same as the statuses of external commands
+    let statuses = run_statuses!(my_exit 2 | my_exit 0).unwrap();
+    assert_eq!(statuses[0].code(), Some(2));
+    assert!(statuses[1].success());
+    assert!(run_cmd!(my_exit 2 | cat).is_err());
+    assert!(run_cmd!(! my_exit 2).is_ok());
+}
+
+#[test]
+fn test_register_cmd() {
+    use std::io::Write;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    // closure capturing its configuration
+    let target = String::from("staging");
+    register_cmd("my_deploy", move |env: &mut CmdEnv| {
+        let app = env.args()[1].clone();
+        writeln!(env.stdout(), "{} to {}", app, target)
+    });
+    assert_eq!(run_fun!(my_deploy app).unwrap(), "app to staging");
+
+    // stateful struct
+    struct Counter(Arc<AtomicUsize>);
+    impl CustomCmd for Counter {
+        fn run(&self, env: &mut CmdEnv) -> CmdResult {
+            let count = self.0.fetch_add(1, Ordering::SeqCst) + 1;
+            writeln!(env.stdout(), "{}", count)
+        }
+    }
+    let count = Arc::new(AtomicUsize::new(0));
+    register_cmd("my_counter", Counter(count.clone()));
+    assert_eq!(run_fun!(my_counter; my_counter | cat).unwrap(), "2");
+    assert_eq!(count.load(Ordering::SeqCst), 2);
+
+    // boxed closure, replacing the previous command
+    let mock: Box<dyn Fn(&mut CmdEnv) -> CmdResult + Send + Sync> =
+        Box::new(|env: &mut CmdEnv| env.exit(3));
+    register_cmd("my_counter", mock);
+    let err = run_cmd!(my_counter).unwrap_err();
+    assert_eq!(CmdError::from_io_error(&err).unwrap().code(), Some(3));
+}